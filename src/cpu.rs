@@ -1,459 +1,731 @@
 use crate::registers::Registers;
 use crate::registers::Flags;
-use crate::memory::{Memory, N_BYTES};
-use crate::op_code::OpCode;
+use crate::memory::{LoadError, Memory, MemoryError};
+use crate::intel_hex::IntelHex;
+use crate::loader::Loader;
+use crate::io_bus::{IoBus, NullIoBus};
+use crate::decoder::{decode, AluOperation, Condition, Instruction, Operand, Register, RegisterPair, RestartVector};
+use std::error::Error;
+use std::fmt;
 use std::fmt::Debug;
 use std::num::Wrapping;
-use std::u8;
-use std::process::exit;
-use std::ops::BitAnd;
+use std::path::Path;
+
+/// What `Cpu::run_for_cycles` does once the CPU executes HLT. Either way the CPU stays
+/// halted until `Cpu::request_interrupt` wakes it up. `Cpu::emulate` has no such setting
+/// and always returns on HLT, as nothing could request an interrupt while it waits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HaltMode {
+    /// Return to the caller as soon as the CPU halts.
+    Stop,
+    /// Idle away the remaining T-states, as the hardware does while waiting for an interrupt.
+    WaitForInterrupt,
+}
+
+/// Record of a single instruction executed by `Cpu::step`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExecutedInstruction {
+    /// Op code fetched.
+    pub op_code: u8,
+    /// Instruction the op code decodes to.
+    pub instruction: Instruction,
+    /// Data following the op code, a byte or a word depending on `instruction.operand()`.
+    pub operand: Option<u16>,
+    /// Address the instruction was fetched from.
+    pub program_counter_before: u16,
+    /// Address of the next instruction, the target of a jump, call or return taken.
+    pub program_counter_after: u16,
+    /// T-states taken, depending on whether a conditional call or return was taken.
+    pub cycles: u8,
+}
+
+/// Result of a successful `Cpu::step`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepOutcome {
+    /// An instruction was executed.
+    Executed(ExecutedInstruction),
+    /// Nothing was executed as the CPU is waiting for an interrupt.
+    Halted,
+}
+
+/// Why `Cpu::emulate` returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmulateOutcome {
+    /// The CPU executed HLT.
+    Halted,
+    /// The program counter reached an address no image was loaded at, such as an empty
+    /// restart vector or code the program copied into RAM. Nothing there was executed.
+    #[allow(missing_docs)]
+    LeftImage { program_counter: u16 },
+}
+
+/// Illegal state the CPU ran into, with the address and op code of the faulting instruction.
+///
+/// Most of what looks illegal is well defined on the 8080 and executes as on hardware: every
+/// op code decodes to an instruction, undocumented ones included, and the stack pointer, the
+/// program counter and all address arithmetic wrap at 16 bits. Popping an empty stack thus
+/// reads whatever is above it rather than underflowing, so there is no stack fault.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuError {
+    /// Write to a read-only region with `RomWritePolicy::Error` in effect. The instruction
+    /// is not executed: registers, memory and the T-state count are left as they were, so
+    /// the CPU can be inspected or the instruction retried.
+    #[allow(missing_docs)]
+    RomWrite { program_counter: u16, op_code: u8, address: u16 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::RomWrite { program_counter, op_code, address } =>
+                write!(f, "write to ROM at {:#06x} from {:#06x} (op code {:#04x})", address, program_counter, op_code),
+        }
+    }
+}
+
+impl Error for CpuError {}
 
+// Failure of a single instruction, turned into a `CpuError` once the faulting instruction is known
 #[derive(Debug)]
-struct Cpu {
+enum Fault {
+    RomWrite(u16),
+}
+
+impl From<MemoryError> for Fault {
+    fn from(error: MemoryError) -> Fault {
+        match error {
+            MemoryError::RomWrite { address, .. } => Fault::RomWrite(address),
+        }
+    }
+}
+
+// Where the instruction being executed comes from
+#[derive(Debug, Clone, Copy)]
+enum Fetch {
+    /// Memory at the program counter, which then moves past the instruction.
+    Memory,
+    /// The data bus during an interrupt acknowledge, leaving the program counter as it is.
+    Interrupt { operand: u16 },
+}
+
+/// Intel 8080 with its registers, memory and I/O bus.
+#[derive(Debug)]
+pub struct Cpu {
     stack_pointer: u16,
     program_counter: u16,
     registers: Registers,
     flags: Flags,
     memory: Memory,
+    io_bus: Box<dyn IoBus>,
+    interrupts_enabled: bool,
+    interrupt_delay: bool,
+    halted: bool,
+    cycles: u64,
 }
 
 impl Cpu {
+    /// CPU without any I/O devices, starting at address 0.
     pub fn new(memory: Memory) -> Cpu {
+        Cpu::with_io_bus(memory, Box::new(NullIoBus))
+    }
+
+    /// CPU with the given devices attached to its I/O ports, starting at address 0.
+    pub fn with_io_bus(memory: Memory, io_bus: Box<dyn IoBus>) -> Cpu {
         Cpu {
             stack_pointer: 0,
             program_counter: 0,
             registers: Registers::new(),
             flags: Flags::new(),
             memory,
+            io_bus,
+            interrupts_enabled: false,
+            interrupt_delay: false,
+            halted: false,
+            cycles: 0,
         }
     }
 
-    pub fn emulate(&mut self) {
-        while (self.program_counter as usize) < self.memory.instructions_len() {
-            let op_code: OpCode = self.memory.fetch_byte_at_offset(self.program_counter).into();
-            self.execute(&op_code);
-        }
+    /// Address of the next instruction.
+    pub fn program_counter(&self) -> u16 {
+        self.program_counter
     }
 
-    fn execute(&mut self, op_code: &OpCode) {
-        match op_code.value {
-            0x00 => {
-                println!("NOP code");
-                self.program_counter += 1;
-            },
-            0x37 => {
-                self.flags.carry = true;
-                self.program_counter += 1;
-            },
-            0x3f => {
-                self.flags.carry = !self.flags.carry;
-                self.program_counter += 1;
-            },
-            0x76 => self.halt(),
-            0x0a | 0x1a => self.load_accumulator(op_code),
-            // STA
-            0x32 => self.store_acc_direct(op_code),
-            // LDA
-            0x3a => self.load_acc_direct(op_code),
-            0x01..=0x3e => self.single_operand_operation(op_code),
-            0x40..=0x7f => self.transfer(op_code),
-            0x80..=0xbf => self.arithmetic_operation(op_code),
-            0xc5 | 0xd5 | 0xe5 | 0xf5 | 0xc6 | 0xe6 | 0xfe => self.single_operand_operation(op_code),
-            0xc4 | 0xcc | 0xcd | 0xd4 | 0xdc | 0xec | 0xf4 | 0xfc => self.call_subroutine(op_code),
-            0xc2 | 0xc3 | 0xca | 0xd2 | 0xda | 0xe2 | 0xea | 0xf2 | 0xfa => self.jump_to_address(op_code),
-            0xeb => self.exchange_registers(op_code),
-            _ => panic!("Unknown op code")
-        }
-    }
-
-    fn should_jump(&mut self, op_code: &OpCode) -> bool {
-        let instruction = op_code.extract_first_operand();
-        let address = self.fetch_operand_addressed_memory();
-        return match instruction {
-            0b0 => {
-                let mut jmp = false;
-                if op_code.extract_jmp_description() > 0 {
-                    jmp = true;
-                } else if !self.flags.zero {
-                    jmp = true;
-                }
-                jmp
-            },
-            // TODO: Missing tests
-            0b001 => self.flags.zero,
-            0b010 => !self.flags.carry,
-            0b011 => self.flags.carry,
-            0b100 => !self.flags.parity,
-            0b101 => self.flags.parity,
-            0b110 => !self.flags.sign,
-            0b111 => self.flags.sign,
-            _ => panic!("Unknown jump description")
-        };
+    /// Continues execution at `program_counter`.
+    pub fn set_program_counter(&mut self, program_counter: u16) {
+        self.program_counter = program_counter;
     }
 
-    fn call_subroutine(&mut self, op_code: &OpCode) {
-        if self.should_jump(op_code) {
-            self.program_counter += 1;
-            let upper = (self.program_counter.bitand(0b1111_1111) >> 7) as u8;
-            let lower = self.program_counter as u8;
-            self.memory.set_byte_at_offset(self.stack_pointer - 1, upper);
-            self.memory.set_byte_at_offset(self.stack_pointer - 2, lower);
-            self.stack_pointer -= 2;
-        } else {
-            self.program_counter += 1;
-        }
+    /// Address of the top of the stack.
+    pub fn stack_pointer(&self) -> u16 {
+        self.stack_pointer
     }
 
-    fn jump_to_address(&mut self, op_code: &OpCode) {
-        let result: bool = self.should_jump(op_code);
-        if result {
-            self.program_counter = address;
-        } else {
-            self.program_counter += 1;
-        }
+    /// Moves the top of the stack to `stack_pointer`, as LXI SP does.
+    pub fn set_stack_pointer(&mut self, stack_pointer: u16) {
+        self.stack_pointer = stack_pointer;
     }
 
-    fn load_acc_direct(&mut self, op_code: &OpCode) {
-        let address = self.fetch_operand_addressed_memory();
-        let value = self.memory.fetch_byte_at_offset(address);
-        self.registers.acc = value;
+    /// General purpose registers and the accumulator.
+    pub fn registers(&self) -> &Registers {
+        &self.registers
     }
 
-    fn store_acc_direct(&mut self, op_code: &OpCode) {
-        let address = self.fetch_operand_addressed_memory();
-        self.memory.set_byte_at_offset(address, self.registers.acc);
+    /// Mutable access to the registers, to set up a test or a calling convention.
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
     }
 
-    fn fetch_operand_addressed_memory(&mut self) -> u16 {
-        let msb = self.memory.fetch_byte_at_offset(self.program_counter + 2);
-        let lsb = self.memory.fetch_byte_at_offset(self.program_counter + 1);
-        self.program_counter += 2;
-        (((msb as u16) << 8) | (lsb as u16))
+    /// Condition flags.
+    pub fn flags(&self) -> &Flags {
+        &self.flags
     }
 
-    fn exchange_registers(&mut self, op_code: &OpCode) {
-        let mut tmp = self.registers.h;
-        self.registers.h = self.registers.d;
-        self.registers.d = tmp;
-        tmp = self.registers.l;
-        self.registers.l = self.registers.e;
-        self.registers.e = tmp;
-        self.program_counter += 1;
+    /// Mutable access to the condition flags.
+    pub fn flags_mut(&mut self) -> &mut Flags {
+        &mut self.flags
     }
 
-    fn load_accumulator(&mut self, op_code: &OpCode) {
-        let registry_pair = op_code.extract_registry_pair_description();
-        let address;
-        if registry_pair == 0 {
-            address = self.registers.get_de()
-        } else {
-            address = self.registers.get_bc()
+    /// Memory the CPU executes out of.
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// Mutable access to memory, for loading images or patching code.
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// Loads an object file into memory, jumping to its start address if it states one.
+    pub fn load_with(&mut self, loader: &dyn Loader, input: &str) -> Result<(), LoadError> {
+        let start_address = self.memory.load_with(loader, input)?;
+        self.jump_to_start(start_address);
+        Ok(())
+    }
+
+    /// `load_with` for Intel HEX.
+    pub fn load_intel_hex(&mut self, input: &str) -> Result<(), LoadError> {
+        self.load_with(&IntelHex, input)
+    }
+
+    /// Like `load_with`, detecting the format as `Memory::load_detected` does.
+    pub fn load_detected(&mut self, contents: &[u8], origin: u16) -> Result<(), LoadError> {
+        let start_address = self.memory.load_detected(contents, origin)?;
+        self.jump_to_start(start_address);
+        Ok(())
+    }
+
+    /// Like `load_detected`, reading the contents from `path`.
+    pub fn load_file(&mut self, path: &Path, origin: u16) -> Result<(), LoadError> {
+        let start_address = self.memory.load_file(path, origin)?;
+        self.jump_to_start(start_address);
+        Ok(())
+    }
+
+    /// Whether the CPU executed HLT and no interrupt was accepted since.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// T-states elapsed since the CPU was created.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// State of the INTE flip-flop, set by EI and reset by DI and accepted interrupts.
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts_enabled
+    }
+
+    /// Requests an interrupt, executing `op_code` as supplied on the data bus, normally an
+    /// RST, if the interrupt system is enabled. Returns whether the interrupt was accepted.
+    /// An instruction with an operand reads it as 0xFFFF, the value of a floating bus; use
+    /// `request_interrupt_with_operand` for controllers that supply one. If the instruction
+    /// faults, the CPU is left as it was before the request.
+    pub fn request_interrupt(&mut self, op_code: u8) -> Result<bool, CpuError> {
+        self.request_interrupt_with_operand(op_code, 0xffff)
+    }
+
+    /// Like `request_interrupt`, with the bytes following the op code on the data bus, such
+    /// as the address of the CALL an 8259 supplies.
+    pub fn request_interrupt_with_operand(&mut self, op_code: u8, operand: u16) -> Result<bool, CpuError> {
+        if !self.interrupts_enabled || self.interrupt_delay {
+            return Ok(false);
         }
-        self.registers.acc = self.memory.fetch_byte_at_offset(address);
-        self.program_counter += 1;
+        let halted = self.halted;
+        self.interrupts_enabled = false;
+        self.halted = false;
+        let program_counter = self.program_counter;
+        if let Err(fault) = self.execute(op_code, Fetch::Interrupt { operand }) {
+            self.interrupts_enabled = true;
+            self.halted = halted;
+            return Err(self.to_cpu_error(fault, program_counter, op_code));
+        }
+        Ok(true)
     }
 
-    fn single_operand_operation(&mut self, op_code: &OpCode) {
-        let operation = (op_code.extract_single_registry_operation(), op_code.extract_second_operand());
-        let encoded_address = op_code.extract_first_operand();
-        match operation {
-            // INR
-            (0b0, 0b100) => {
-                let mut value = Wrapping(self.extract_memory_or_register(encoded_address));
-                value += Wrapping(1);
-                self.change_single_registry_value(encoded_address, value.0);
-            },
-            // DCR
-            (0b0, 0b101) => {
-                let mut value = Wrapping(self.extract_memory_or_register(encoded_address));
-                value -= Wrapping(1);
-                self.change_single_registry_value(encoded_address, value.0);
-            },
-            // ROTATE
-            (0b0, 0b111) => {
-                self.rotate_acc(encoded_address);
+    /// Executes a single instruction. A halted CPU executes nothing until an interrupt.
+    pub fn step(&mut self) -> Result<StepOutcome, CpuError> {
+        if self.halted {
+            return Ok(StepOutcome::Halted);
+        }
+        let program_counter = self.program_counter;
+        let op_code = self.read_byte(program_counter);
+        let executed = self.execute(op_code, Fetch::Memory)
+            .map_err(|fault| self.to_cpu_error(fault, program_counter, op_code))?;
+        Ok(StepOutcome::Executed(executed))
+    }
+
+    /// Runs until the CPU halts or the program counter leaves the images loaded into memory.
+    /// It returns on HLT whether or not interrupts are enabled, to wait for one use
+    /// `run_for_cycles` with `HaltMode::WaitForInterrupt`. A halted CPU resumes on the next
+    /// call once an interrupt was accepted, code outside the images can still be run with
+    /// `step` or `run_for_cycles`.
+    pub fn emulate(&mut self) -> Result<EmulateOutcome, CpuError> {
+        loop {
+            if self.halted {
+                return Ok(EmulateOutcome::Halted);
+            }
+            if !self.memory.is_in_image(self.program_counter) {
+                return Ok(EmulateOutcome::LeftImage { program_counter: self.program_counter });
+            }
+            self.step()?;
+        }
+    }
+
+    /// Runs until at least the given number of T-states has elapsed and returns the number
+    /// actually elapsed, which overshoots by at most the last instruction. A halted CPU
+    /// returns early in `HaltMode::Stop` and idles away the remaining T-states otherwise.
+    pub fn run_for_cycles(&mut self, cycles: u64, halt_mode: HaltMode) -> Result<u64, CpuError> {
+        let start = self.cycles;
+        let target = start.saturating_add(cycles);
+        while self.cycles < target {
+            if self.halted {
+                if halt_mode == HaltMode::WaitForInterrupt {
+                    self.cycles = target;
+                }
+                break;
+            }
+            self.step()?;
+        }
+        Ok(self.cycles - start)
+    }
+
+    fn jump_to_start(&mut self, start_address: Option<u16>) {
+        if let Some(start_address) = start_address {
+            self.program_counter = start_address;
+        }
+    }
+
+    fn to_cpu_error(&self, fault: Fault, program_counter: u16, op_code: u8) -> CpuError {
+        match fault {
+            Fault::RomWrite(address) => CpuError::RomWrite { program_counter, op_code, address },
+        }
+    }
+
+    fn execute(&mut self, op_code: u8, fetch: Fetch) -> Result<ExecutedInstruction, Fault> {
+        // Only memory writes fault, and those are checked before anything is written, so
+        // restoring the registers is enough to undo the instruction
+        let saved = (self.program_counter, self.stack_pointer, self.registers, self.flags, self.interrupt_delay);
+        self.execute_decoded(op_code, fetch).inspect_err(|_| {
+            let (program_counter, stack_pointer, registers, flags, interrupt_delay) = saved;
+            self.program_counter = program_counter;
+            self.stack_pointer = stack_pointer;
+            self.registers = registers;
+            self.flags = flags;
+            self.interrupt_delay = interrupt_delay;
+        })
+    }
+
+    fn execute_decoded(&mut self, op_code: u8, fetch: Fetch) -> Result<ExecutedInstruction, Fault> {
+        let instruction = decode(op_code);
+        self.interrupt_delay = false;
+        let cycles = match instruction {
+            Instruction::Ccc(condition) | Instruction::Rcc(condition) if self.should_jump(Some(condition)) => {
+                instruction.cycles_when_taken()
             },
-            //DAD
-            (0b0, 0b001) => {
-                self.double_add(encoded_address);
+            _ => instruction.cycles(),
+        };
+        let program_counter_before = self.program_counter;
+        let operand = match fetch {
+            Fetch::Memory => {
+                let operand = self.fetch_operand(instruction.operand());
+                self.program_counter = self.program_counter.wrapping_add(instruction.length() as u16);
+                operand
             },
-            // INX
-            (0b0, 0b011) => {
-                self.increment_double(encoded_address);
+            Fetch::Interrupt { operand } => match instruction.operand() {
+                Operand::None => 0,
+                Operand::Byte => operand & 0xff,
+                Operand::Word => operand,
             },
-            // PUSH
-            (0b11, 0b101) => {
-                self.push_on_stack(encoded_address);
+        };
+        match instruction {
+            Instruction::Nop => {},
+            Instruction::Lxi(register_pair) => self.set_register_pair(register_pair, operand),
+            Instruction::Stax(register_pair) => {
+                let address = self.get_register_pair(register_pair);
+                self.write_byte(address, self.registers.acc)?;
             },
-            // POP
-            (0b11, 0b1) => {
-                self.pop_off_stack(encoded_address);
+            Instruction::Ldax(register_pair) => {
+                let address = self.get_register_pair(register_pair);
+                self.registers.acc = self.read_byte(address);
             },
-            // MVI
-            (0b0, 0b110) => {
-                self.move_immediate(encoded_address);
+            Instruction::Shld => self.store_hl_direct(operand)?,
+            Instruction::Lhld => self.load_hl_direct(operand),
+            Instruction::Sta => self.write_byte(operand, self.registers.acc)?,
+            Instruction::Lda => self.registers.acc = self.read_byte(operand),
+            Instruction::Inx(register_pair) => self.increment_double(register_pair),
+            Instruction::Dcx(register_pair) => self.decrement_double(register_pair),
+            Instruction::Inr(register) => self.increment(register)?,
+            Instruction::Dcr(register) => self.decrement(register)?,
+            Instruction::Mvi(register) => self.set_memory_or_register(register, operand as u8)?,
+            Instruction::Rlc => self.rotate_acc_left(false),
+            Instruction::Rrc => self.rotate_acc_right(false),
+            Instruction::Ral => self.rotate_acc_left(true),
+            Instruction::Rar => self.rotate_acc_right(true),
+            Instruction::Daa => self.decimal_adjust(),
+            Instruction::Cma => self.registers.acc = !self.registers.acc,
+            Instruction::Stc => self.flags.carry = true,
+            Instruction::Cmc => self.flags.carry = !self.flags.carry,
+            Instruction::Dad(register_pair) => self.double_add(register_pair),
+            Instruction::Mov(destination, source) => self.transfer(destination, source)?,
+            Instruction::Hlt => self.halted = true,
+            Instruction::Alu(operation, register) => {
+                let value = self.extract_memory_or_register(register);
+                self.arithmetic_operation(operation, value);
             },
-            // ADI
-            (0b11, 0b110) => {
-                self.immediate_arithmetic(encoded_address);
+            Instruction::AluImmediate(operation) => self.arithmetic_operation(operation, operand as u8),
+            Instruction::Rcc(condition) => self.return_from_subroutine(Some(condition)),
+            Instruction::Ret => self.return_from_subroutine(None),
+            Instruction::Pop(register_pair) => self.pop_off_stack(register_pair),
+            Instruction::Push(register_pair) => self.push_on_stack(register_pair)?,
+            Instruction::Jcc(condition) => self.jump_to_address(Some(condition), operand),
+            Instruction::Jmp => self.jump_to_address(None, operand),
+            Instruction::Ccc(condition) => self.call_subroutine(Some(condition), operand)?,
+            Instruction::Call => self.call_subroutine(None, operand)?,
+            Instruction::Rst(vector) => self.restart(vector)?,
+            Instruction::Out => self.io_bus.write_port(operand as u8, self.registers.acc),
+            Instruction::In => self.registers.acc = self.io_bus.read_port(operand as u8),
+            Instruction::Xthl => self.exchange_stack_top()?,
+            Instruction::Pchl => self.program_counter = self.registers.get_hl(),
+            Instruction::Xchg => self.exchange_registers(),
+            Instruction::Sphl => self.stack_pointer = self.registers.get_hl(),
+            Instruction::Di => self.interrupts_enabled = false,
+            Instruction::Ei => {
+                self.interrupts_enabled = true;
+                self.interrupt_delay = true;
             },
-            _ => panic!("Unknown single registry operation")
         }
+        self.cycles += cycles as u64;
+        Ok(ExecutedInstruction {
+            op_code,
+            instruction,
+            operand: if instruction.operand() == Operand::None { None } else { Some(operand) },
+            program_counter_before,
+            program_counter_after: self.program_counter,
+            cycles,
+        })
+    }
 
-        self.program_counter += 1;
+    fn read_byte(&self, address: u16) -> u8 {
+        self.memory.fetch_byte_at_offset(address)
     }
 
-    fn immediate_arithmetic(&mut self, operation: u8) {
-        self.program_counter += 1;
-        let data = self.memory.fetch_byte_at_offset(self.program_counter);
-        match operation {
-            0b0 => self.add(data, false),
-            0b100 => self.and(data),
-            0b111 => self.comparison(data),
-            // TODO: Needs test
-            0b001 => self.add(data, true),
-            0b010 => self.subtract(data, false),
-            0b011 => self.subtract(data, true),
-            0b101 => self.xor(data),
-            0b110 => self.or(data),
-            _ => panic!("Unknown immediate operation")
-        }
-    }
-
-    fn move_immediate(&mut self, address: u8) {
-        self.program_counter += 1;
-        let data = self.memory.fetch_byte_at_offset(self.program_counter);
-        self.set_memory_or_register(address, data);
-    }
-
-    fn increment_double(&mut self, address: u8) {
-        match address {
-            0b0 => {
-                let mut value = Wrapping(self.registers.get_bc());
-                value += Wrapping(1);
-                self.registers.set_bc(value.0);
-            },
-            0b010 => {
-                let mut value = Wrapping(self.registers.get_de());
-                value += Wrapping(1);
-                self.registers.set_de(value.0);
-            },
-            0b100 => {
-                let mut value = Wrapping(self.registers.get_hl());
-                value += Wrapping(1);
-                self.registers.set_hl(value.0);
-            },
-            0b110 => {
-                let mut value = Wrapping(self.stack_pointer);
-                value += Wrapping(1);
-                self.stack_pointer = value.0;
-            },
-            _ => panic!("Unknown register pair")
+    fn write_byte(&mut self, address: u16, value: u8) -> Result<(), Fault> {
+        self.memory.set_byte_at_offset(address, value).map_err(Fault::from)
+    }
+
+    fn read_word(&self, address: u16) -> u16 {
+        let lsb = self.read_byte(address);
+        let msb = self.read_byte(address.wrapping_add(1));
+        ((msb as u16) << 8) | (lsb as u16)
+    }
+
+    // Writes neither byte unless both can be written
+    fn write_word(&mut self, address: u16, value: u16) -> Result<(), Fault> {
+        let bytes = [(address, value as u8), (address.wrapping_add(1), (value >> 8) as u8)];
+        for &(address, value) in bytes.iter() {
+            self.memory.check_write(address, value).map_err(Fault::from)?;
         }
+        for &(address, value) in bytes.iter() {
+            self.write_byte(address, value)?;
+        }
+        Ok(())
     }
 
-    fn double_add(&mut self, address: u8) {
-        let value;
-        match address {
-            0b001 => {
-                value = self.registers.get_bc();
-            },
-            0b011 => {
-                value = self.registers.get_de();
-            },
-            0b101 => {
-                value = self.registers.get_hl();
-            },
-            0b111 => {
-                value = self.stack_pointer;
-            },
-            _ => panic!("Unknown register pair")
+    fn fetch_operand(&self, operand: Operand) -> u16 {
+        match operand {
+            Operand::None => 0,
+            Operand::Byte => self.read_byte(self.program_counter.wrapping_add(1)) as u16,
+            Operand::Word => self.read_word(self.program_counter.wrapping_add(1)),
         }
-        let result: u32 = self.registers.get_hl() as u32 + value as u32;
-        self.flags.set_carry_on_double(result);
-        self.registers.set_hl(result as u16);
     }
 
-    fn push_on_stack(&mut self, address: u8) {
-        let upper;
-        let lower;
-        match address {
-            0b0 => {
-                upper = self.registers.b;
-                lower = self.registers.c;
-            },
-            0b010 => {
-                upper = self.registers.d;
-                lower = self.registers.e;
-            },
-            0b100 => {
-                upper = self.registers.h;
-                lower = self.registers.l;
-            },
-            0b110 => {
-                upper = self.registers.acc;
-                lower = ((self.flags.sign as u8) << 7) |
-                         ((self.flags.zero as u8) << 6) |
-                         ((self.flags.aux_carry as u8) << 4) |
-                         ((self.flags.parity as u8) << 2) |
-                         (0b00000010) |
-                        (self.flags.carry as u8)
-            },
-            _ => panic!("Unknown register pair")
+    fn should_jump(&self, condition: Option<Condition>) -> bool {
+        match condition {
+            None => true,
+            Some(Condition::NotZero) => !self.flags.zero,
+            Some(Condition::Zero) => self.flags.zero,
+            Some(Condition::NoCarry) => !self.flags.carry,
+            Some(Condition::Carry) => self.flags.carry,
+            Some(Condition::ParityOdd) => !self.flags.parity,
+            Some(Condition::ParityEven) => self.flags.parity,
+            Some(Condition::Plus) => !self.flags.sign,
+            Some(Condition::Minus) => self.flags.sign,
         }
-        self.memory.set_byte_at_offset(self.stack_pointer - 1, upper);
-        self.memory.set_byte_at_offset(self.stack_pointer - 2, lower);
-        self.stack_pointer -= 2;
     }
 
-    fn pop_off_stack(&mut self, destination: u8) {
-        let upper = self.memory.fetch_byte_at_offset(self.stack_pointer);
-        let lower = self.memory.fetch_byte_at_offset(self.stack_pointer + 1);
-        match destination {
-            0b0 => {
-                self.registers.b = lower;
-                self.registers.c = upper;
-            },
-            0b010 => {
-                self.registers.d = lower;
-                self.registers.e = upper;
-            },
-            0b100 => {
-                self.registers.h = lower;
-                self.registers.l = upper;
-            },
-            0b110 => {
-                self.registers.acc = lower;
-                self.flags.sign = upper << 7 != 0;
-                self.flags.zero = upper << 6 != 0;
-                self.flags.aux_carry = upper << 4 != 0;
-                self.flags.parity = upper << 2 != 0;
-                self.flags.carry = upper != 0;
-            },
-            _ => panic!("Unknown register pair")
+    fn call_subroutine(&mut self, condition: Option<Condition>, address: u16) -> Result<(), Fault> {
+        if self.should_jump(condition) {
+            self.push_address(self.program_counter)?;
+            self.program_counter = address;
         }
-        self.stack_pointer += 2;
+        Ok(())
     }
 
-    fn rotate_acc(&mut self, direction: u8) {
-        match direction {
-            // RLC
-            0b0 => {
-                self.flags.carry = (self.registers.acc & 0b10000000) != 0;
-                self.registers.acc = self.registers.acc.rotate_left(1)
-            },
-            // RRC
-            0b01 => {
-                self.flags.carry = (self.registers.acc & 0b00000001) != 0;
-                self.registers.acc = self.registers.acc.rotate_right(1)
-            }
-            // missing RAL, RAR
-            _ => panic!("Unknown rotation direction")
+    fn return_from_subroutine(&mut self, condition: Option<Condition>) {
+        if self.should_jump(condition) {
+            self.program_counter = self.pop_address();
         }
     }
 
-    fn change_single_registry_value(&mut self, encoded_address: u8, value: u8) {
-        self.flags.set_single_registry_operation_flags(value as u16);
-        self.set_memory_or_register(encoded_address, value)
+    fn restart(&mut self, vector: RestartVector) -> Result<(), Fault> {
+        self.push_address(self.program_counter)?;
+        self.program_counter = vector.address();
+        Ok(())
     }
 
-    fn transfer(&mut self, op_code: &OpCode) {
-        let source = self.extract_source_value(op_code);
-        let encoded_dest = op_code.extract_first_operand();
-        self.set_memory_or_register(encoded_dest, source as u8);
-        self.program_counter += 1;
+    // The stack pointer only moves once the write went through
+    fn push_address(&mut self, address: u16) -> Result<(), Fault> {
+        let stack_pointer = self.stack_pointer.wrapping_sub(2);
+        self.write_word(stack_pointer, address)?;
+        self.stack_pointer = stack_pointer;
+        Ok(())
     }
 
-    fn extract_source_value(&mut self, op_code: &OpCode) -> u8 {
-        let encoded_source = op_code.extract_second_operand();
-        println!("source register: {:#b}", encoded_source);
-        return self.extract_memory_or_register(encoded_source);
+    fn pop_address(&mut self) -> u16 {
+        let address = self.read_word(self.stack_pointer);
+        self.stack_pointer = self.stack_pointer.wrapping_add(2);
+        address
     }
 
-    fn extract_memory_or_register(&mut self, encoded_source: u8) -> u8 {
-        if encoded_source == 0b110 {
-            return self.memory.fetch_byte_at_offset(self.registers.get_hl())
+    fn jump_to_address(&mut self, condition: Option<Condition>, address: u16) {
+        if self.should_jump(condition) {
+            self.program_counter = address;
+        }
+    }
+
+    fn load_hl_direct(&mut self, address: u16) {
+        let value = self.read_word(address);
+        self.registers.set_hl(value);
+    }
+
+    fn store_hl_direct(&mut self, address: u16) -> Result<(), Fault> {
+        self.write_word(address, self.registers.get_hl())
+    }
+
+    fn increment_double(&mut self, register_pair: RegisterPair) {
+        let value = self.get_register_pair(register_pair).wrapping_add(1);
+        self.set_register_pair(register_pair, value);
+    }
+
+    fn decrement_double(&mut self, register_pair: RegisterPair) {
+        let value = self.get_register_pair(register_pair).wrapping_sub(1);
+        self.set_register_pair(register_pair, value);
+    }
+
+    fn exchange_stack_top(&mut self) -> Result<(), Fault> {
+        let value = self.read_word(self.stack_pointer);
+        self.write_word(self.stack_pointer, self.registers.get_hl())?;
+        self.registers.set_hl(value);
+        Ok(())
+    }
+
+    fn get_register_pair(&self, register_pair: RegisterPair) -> u16 {
+        match register_pair {
+            RegisterPair::BC => self.registers.get_bc(),
+            RegisterPair::DE => self.registers.get_de(),
+            RegisterPair::HL => self.registers.get_hl(),
+            RegisterPair::SP => self.stack_pointer,
+            RegisterPair::PSW => ((self.registers.acc as u16) << 8) | (self.flags.as_byte() as u16),
         }
-        return *self.extract_register_address(encoded_source)
     }
 
-    fn set_memory_or_register(&mut self, encoded_address: u8, value: u8) {
-        if encoded_address == 0b110 {
-            self.memory.set_byte_at_offset(self.registers.get_hl(), value);
+    fn set_register_pair(&mut self, register_pair: RegisterPair, value: u16) {
+        match register_pair {
+            RegisterPair::BC => self.registers.set_bc(value),
+            RegisterPair::DE => self.registers.set_de(value),
+            RegisterPair::HL => self.registers.set_hl(value),
+            RegisterPair::SP => self.stack_pointer = value,
+            RegisterPair::PSW => {
+                self.registers.acc = (value >> 8) as u8;
+                self.flags.set_from_byte(value as u8);
+            },
+        }
+    }
+
+    fn exchange_registers(&mut self) {
+        std::mem::swap(&mut self.registers.h, &mut self.registers.d);
+        std::mem::swap(&mut self.registers.l, &mut self.registers.e);
+    }
+
+    fn increment(&mut self, register: Register) -> Result<(), Fault> {
+        let mut value = Wrapping(self.extract_memory_or_register(register));
+        value += Wrapping(1);
+        self.flags.set_aux_carry_on_increment(value.0);
+        self.change_single_registry_value(register, value.0)
+    }
+
+    fn decrement(&mut self, register: Register) -> Result<(), Fault> {
+        let mut value = Wrapping(self.extract_memory_or_register(register));
+        value -= Wrapping(1);
+        self.flags.set_aux_carry_on_decrement(value.0);
+        self.change_single_registry_value(register, value.0)
+    }
+
+    fn double_add(&mut self, register_pair: RegisterPair) {
+        let value = self.get_register_pair(register_pair);
+        let result: u32 = self.registers.get_hl() as u32 + value as u32;
+        self.flags.set_carry_on_double(result);
+        self.registers.set_hl(result as u16);
+    }
+
+    fn push_on_stack(&mut self, register_pair: RegisterPair) -> Result<(), Fault> {
+        let value = self.get_register_pair(register_pair);
+        self.push_address(value)
+    }
+
+    fn pop_off_stack(&mut self, register_pair: RegisterPair) {
+        let value = self.pop_address();
+        self.set_register_pair(register_pair, value);
+    }
+
+    fn rotate_acc_left(&mut self, through_carry: bool) {
+        let carry = if through_carry {
+            self.flags.carry
+        } else {
+            (self.registers.acc & 0b10000000) != 0
+        };
+        self.flags.carry = (self.registers.acc & 0b10000000) != 0;
+        self.registers.acc = (self.registers.acc << 1) | (carry as u8);
+    }
+
+    fn rotate_acc_right(&mut self, through_carry: bool) {
+        let carry = if through_carry {
+            self.flags.carry
         } else {
-            *self.extract_register_address(encoded_address) = value;
+            (self.registers.acc & 0b00000001) != 0
+        };
+        self.flags.carry = (self.registers.acc & 0b00000001) != 0;
+        self.registers.acc = (self.registers.acc >> 1) | ((carry as u8) << 7);
+    }
+
+    fn decimal_adjust(&mut self) {
+        let low_nibble = self.registers.acc & 0x0F;
+        let high_nibble = self.registers.acc >> 4;
+        let mut correction = 0;
+        let mut carry = self.flags.carry;
+        if low_nibble > 9 || self.flags.aux_carry {
+            correction |= 0x06;
+        }
+        if high_nibble > 9 || (high_nibble >= 9 && low_nibble > 9) || carry {
+            correction |= 0x60;
+            carry = true;
         }
+        self.add(correction, false);
+        self.flags.carry = carry;
+    }
+
+    fn change_single_registry_value(&mut self, register: Register, value: u8) -> Result<(), Fault> {
+        self.flags.set_single_registry_operation_flags(value as u16);
+        self.set_memory_or_register(register, value)
+    }
+
+    fn transfer(&mut self, destination: Register, source: Register) -> Result<(), Fault> {
+        let value = self.extract_memory_or_register(source);
+        self.set_memory_or_register(destination, value)
     }
 
-    fn extract_register_address(&mut self, encoded: u8) -> &mut u8 {
-        match encoded {
-            0b0 => &mut self.registers.b,
-            0b001 => &mut self.registers.c,
-            0b010 => &mut self.registers.d,
-            0b011 => &mut self.registers.e,
-            0b100 => &mut self.registers.h,
-            0b101 => &mut self.registers.l,
-            0b111 => &mut self.registers.acc,
-            _ => panic!("Unknown address")
+    fn extract_memory_or_register(&self, register: Register) -> u8 {
+        match register {
+            Register::B => self.registers.b,
+            Register::C => self.registers.c,
+            Register::D => self.registers.d,
+            Register::E => self.registers.e,
+            Register::H => self.registers.h,
+            Register::L => self.registers.l,
+            Register::M => self.read_byte(self.registers.get_hl()),
+            Register::A => self.registers.acc,
         }
     }
 
-    fn halt(&self) {
-        unimplemented!();
+    fn set_memory_or_register(&mut self, register: Register, value: u8) -> Result<(), Fault> {
+        match register {
+            Register::B => self.registers.b = value,
+            Register::C => self.registers.c = value,
+            Register::D => self.registers.d = value,
+            Register::E => self.registers.e = value,
+            Register::H => self.registers.h = value,
+            Register::L => self.registers.l = value,
+            Register::M => self.write_byte(self.registers.get_hl(), value)?,
+            Register::A => self.registers.acc = value,
+        }
+        Ok(())
     }
 
-    fn arithmetic_operation(&mut self, op_code: &OpCode) {
-        let encoded_operation = op_code.extract_first_operand();
-        let encoded_addend = op_code.extract_second_operand();
-        let value = self.extract_memory_or_register(encoded_addend);
-        match encoded_operation {
-            0b000 => self.add(value, false),
-            0b001 => self.add(value, self.flags.carry),
-            0b010 => self.subtract(value, false),
-            0b011 => self.subtract(value, self.flags.carry),
-            0b100 => self.and(value),
-            0b101 => self.xor(value),
-            0b110 => self.or(value),
-            0b111 => self.comparison(value),
-            _ => println!("Could not decode arithmetic operation")
+    fn arithmetic_operation(&mut self, operation: AluOperation, value: u8) {
+        match operation {
+            AluOperation::Add => self.add(value, false),
+            AluOperation::AddWithCarry => self.add(value, self.flags.carry),
+            AluOperation::Subtract => self.subtract(value, false),
+            AluOperation::SubtractWithBorrow => self.subtract(value, self.flags.carry),
+            AluOperation::And => self.and(value),
+            AluOperation::Xor => self.xor(value),
+            AluOperation::Or => self.or(value),
+            AluOperation::Compare => self.comparison(value),
         }
-        self.program_counter += 1;
     }
 
     // TODO: Refactor - repetition
     fn add(&mut self, value: u8, carry: bool) {
         let result: u16 = (self.registers.acc as u16) + (value as u16) + (carry as u16);
         self.flags.set_all(result);
+        self.flags.set_aux_carry_on_add(self.registers.acc, value, carry);
         self.registers.acc = result as u8;
     }
 
     fn subtract(&mut self, value: u8, carry: bool) {
         let result = (Wrapping(self.registers.acc as u16) - Wrapping(value as u16 + carry as u16)).0;
         self.flags.set_all(result);
+        self.flags.set_aux_carry_on_subtract(self.registers.acc, value, carry);
         self.registers.acc = result as u8;
     }
 
     fn and(&mut self, value: u8) {
         let result = self.registers.acc as u16 & value as u16;
         self.flags.set_all(result);
+        // ANA sets AC from bit 3 of the operands rather than from the result
+        self.flags.aux_carry = ((self.registers.acc | value) & 0b00001000) != 0;
         self.registers.acc = result as u8;
     }
 
     fn or(&mut self, value: u8) {
         let result = self.registers.acc as u16 | value as u16;
         self.flags.set_all(result);
+        self.flags.aux_carry = false;
         self.registers.acc = result as u8;
     }
 
     fn xor(&mut self, value: u8) {
         let result = self.registers.acc as u16 ^ value as u16;
         self.flags.set_all(result);
+        self.flags.aux_carry = false;
         self.registers.acc = result as u8;
     }
 
     fn comparison(&mut self, value: u8) {
-        let result = Wrapping(self.registers.acc) - Wrapping(value);
-        self.flags.set_all(result.0 as u16)
+        let result = (Wrapping(self.registers.acc as u16) - Wrapping(value as u16)).0;
+        self.flags.set_all(result);
+        self.flags.set_aux_carry_on_subtract(self.registers.acc, value, false);
     }
 }
 
@@ -461,20 +733,40 @@ impl Cpu {
 mod tests {
 
     use super::*;
-    use std::u8;
+    use crate::io_bus::RecordingIoBus;
+    use crate::loader::FormatError;
+    use crate::memory::{BankSelector, RomWritePolicy};
 
     fn create_test_cpu(input: Vec<u8>) -> Cpu {
-        let mut memory = Memory::new(input);
+        let memory = Memory::with_image(0, &input).unwrap();
         Cpu::new(memory)
     }
 
+    fn set_condition(flags: &mut Flags, condition: u8, taken: bool) {
+        match condition {
+            0b000 => flags.zero = !taken,
+            0b001 => flags.zero = taken,
+            0b010 => flags.carry = !taken,
+            0b011 => flags.carry = taken,
+            0b100 => flags.parity = !taken,
+            0b101 => flags.parity = taken,
+            0b110 => flags.sign = !taken,
+            0b111 => flags.sign = taken,
+            _ => panic!("Unknown condition")
+        }
+    }
+
+    fn execute_next(cpu: &mut Cpu) {
+        cpu.step().unwrap();
+    }
+
     #[test]
     fn test_transfer_register() {
         let mut cpu = create_test_cpu(vec![0x50]);
         let result = 8;
         cpu.registers.d = 12;
         cpu.registers.b = result;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.d, result)
     }
 
@@ -483,20 +775,24 @@ mod tests {
         let mut cpu = create_test_cpu(vec![0x49]);
         let result = 8;
         cpu.registers.c = result;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.c, result);
         assert_ne!(cpu.registers.b, result)
     }
 
     #[test]
-    #[should_panic]
-    // TODO: Wrap
     fn test_transfer_memory() {
         let result = 15;
         let mut cpu = create_test_cpu(vec![0x66, result]);
         cpu.registers.h = 0;
-        cpu.registers.b = 2;
-        cpu.emulate();
+        cpu.registers.l = 1;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.h, result);
+
+        let mut cpu = create_test_cpu(vec![0x66]);
+        cpu.memory.set_byte_at_offset(0xFFFF, result).unwrap();
+        cpu.registers.set_hl(0xFFFF);
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.h, result)
     }
 
@@ -505,18 +801,18 @@ mod tests {
         let mut cpu = create_test_cpu(vec![0x81]);
         cpu.registers.acc = 8;
         cpu.registers.c = 12;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 20)
     }
 
     #[test]
     fn test_add_overflow() {
         let mut cpu = create_test_cpu(vec![0x81]);
-        cpu.registers.acc = u8::max_value();
+        cpu.registers.acc = u8::MAX;
         cpu.registers.c = 1;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 0);
-        assert_eq!(cpu.flags.carry, true)
+        assert!(cpu.flags.carry)
     }
 
     #[test]
@@ -525,7 +821,7 @@ mod tests {
         cpu.registers.acc = 8;
         cpu.registers.d = 12;
         cpu.flags.carry = true;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 21)
     }
 
@@ -534,8 +830,8 @@ mod tests {
         let mut cpu = create_test_cpu(vec![0x83]);
         cpu.registers.acc = 0;
         cpu.registers.e = 0;
-        cpu.emulate();
-        assert_eq!(cpu.flags.zero, true)
+        cpu.emulate().unwrap();
+        assert!(cpu.flags.zero)
     }
 
     #[test]
@@ -544,8 +840,8 @@ mod tests {
         cpu.registers.acc = 0;
         cpu.registers.e = 10;
         cpu.flags.sign = true;
-        cpu.emulate();
-        assert_eq!(cpu.flags.sign, false)
+        cpu.emulate().unwrap();
+        assert!(!cpu.flags.sign)
     }
 
     #[test]
@@ -553,9 +849,9 @@ mod tests {
         let mut cpu = create_test_cpu(vec![0x97]);
         cpu.registers.acc = 0x3E;
         cpu.flags.carry = true;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 0);
-        assert_eq!(cpu.flags.carry, false)
+        assert!(!cpu.flags.carry)
     }
 
     #[test]
@@ -564,9 +860,9 @@ mod tests {
         cpu.registers.acc = 10;
         cpu.registers.b = 3;
         cpu.flags.carry = true;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 6);
-        assert_eq!(cpu.flags.carry, false)
+        assert!(!cpu.flags.carry)
     }
 
     #[test]
@@ -575,9 +871,9 @@ mod tests {
         cpu.registers.acc = 2;
         cpu.registers.b = 3;
         cpu.flags.carry = false;
-        cpu.emulate();
-        assert_eq!(cpu.flags.carry, true);
-        assert_eq!(cpu.registers.acc, u8::max_value())
+        cpu.emulate().unwrap();
+        assert!(cpu.flags.carry);
+        assert_eq!(cpu.registers.acc, u8::MAX)
     }
 
     #[test]
@@ -585,7 +881,7 @@ mod tests {
         let mut cpu = create_test_cpu(vec![0xa0]);
         cpu.registers.acc = 0b11111100;
         cpu.registers.b = 0b00001111;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 0b00001100)
     }
 
@@ -594,7 +890,7 @@ mod tests {
         let mut cpu = create_test_cpu(vec![0xa9]);
         cpu.registers.acc = 0b01011100;
         cpu.registers.c = 0b01111000;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 0b00100100)
     }
 
@@ -603,7 +899,7 @@ mod tests {
         let mut cpu = create_test_cpu(vec![0xb2]);
         cpu.registers.acc = 0b11111100;
         cpu.registers.d = 0b00001111;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 0b11111111)
     }
 
@@ -611,7 +907,7 @@ mod tests {
     fn test_increment() {
         let mut cpu = create_test_cpu(vec![0x04]);
         cpu.registers.b = 1;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.b, 2)
     }
 
@@ -619,9 +915,9 @@ mod tests {
     fn test_decrement() {
         let mut cpu = create_test_cpu(vec![0x0d]);
         cpu.registers.c = 0;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.c, 255);
-        assert_eq!(cpu.flags.carry, false);
+        assert!(!cpu.flags.carry);
     }
 
     #[test]
@@ -629,7 +925,7 @@ mod tests {
         let mut cpu = create_test_cpu(vec![0xb2]);
         cpu.registers.acc = 0b11111100;
         cpu.registers.d = 0b00001111;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 0b11111111)
     }
 
@@ -638,9 +934,9 @@ mod tests {
         let mut cpu = create_test_cpu(vec![0x07]);
         cpu.registers.acc = 0b11110010;
         cpu.flags.carry = false;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 0b11100101);
-        assert_eq!(cpu.flags.carry, true);
+        assert!(cpu.flags.carry);
     }
 
     #[test]
@@ -648,9 +944,55 @@ mod tests {
         let mut cpu = create_test_cpu(vec![0x0F]);
         cpu.registers.acc = 0b11110010;
         cpu.flags.carry = true;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 0b01111001);
-        assert_eq!(cpu.flags.carry, false);
+        assert!(!cpu.flags.carry);
+    }
+
+    #[test]
+    fn test_rotate_left_through_carry() {
+        let mut cpu = create_test_cpu(vec![0x17]);
+        cpu.registers.acc = 0b10110101;
+        cpu.flags.carry = false;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0b01101010);
+        assert!(cpu.flags.carry);
+
+        let mut cpu = create_test_cpu(vec![0x17]);
+        cpu.registers.acc = 0b00110101;
+        cpu.flags.carry = true;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0b01101011);
+        assert!(!cpu.flags.carry);
+    }
+
+    #[test]
+    fn test_rotate_right_through_carry() {
+        let mut cpu = create_test_cpu(vec![0x1f]);
+        cpu.registers.acc = 0b01101010;
+        cpu.flags.carry = true;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0b10110101);
+        assert!(!cpu.flags.carry);
+
+        let mut cpu = create_test_cpu(vec![0x1f]);
+        cpu.registers.acc = 0b01101011;
+        cpu.flags.carry = false;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0b00110101);
+        assert!(cpu.flags.carry);
+    }
+
+    #[test]
+    fn test_complement_acc() {
+        let mut cpu = create_test_cpu(vec![0x2f]);
+        cpu.registers.acc = 0b01010001;
+        cpu.flags.zero = true;
+        cpu.flags.carry = true;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0b10101110);
+        assert!(cpu.flags.zero);
+        assert!(cpu.flags.carry);
     }
 
     #[test]
@@ -659,7 +1001,7 @@ mod tests {
         cpu.stack_pointer = 2;
         cpu.registers.b = 12;
         cpu.registers.c = 18;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.memory.fetch_byte_at_offset(1), 12);
         assert_eq!(cpu.memory.fetch_byte_at_offset(0), 18);
         assert_eq!(cpu.stack_pointer, 0);
@@ -674,7 +1016,7 @@ mod tests {
         cpu.flags.carry = true;
         cpu.flags.zero = false;
         cpu.flags.parity = true;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.memory.fetch_byte_at_offset(1), 12);
         assert_eq!(cpu.memory.fetch_byte_at_offset(0), 0b10000111);
         assert_eq!(cpu.stack_pointer, 0);
@@ -688,11 +1030,11 @@ mod tests {
         cpu.registers.h = 0xa1;
         cpu.registers.l = 0x7b;
         cpu.flags.carry = true;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.get_hl(), 0xd51a);
         assert_eq!(cpu.registers.h, 0xd5);
         assert_eq!(cpu.registers.l, 0x1a);
-        assert_eq!(cpu.flags.carry, false);
+        assert!(!cpu.flags.carry);
     }
 
     #[test]
@@ -701,18 +1043,18 @@ mod tests {
         cpu.registers.d = 0x38;
         cpu.registers.e = 0xff;
         cpu.flags.carry = false;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.get_de(), 0x3900);
         assert_eq!(cpu.registers.d, 0x39);
         assert_eq!(cpu.registers.e, 0x00);
-        assert_eq!(cpu.flags.carry, false);
+        assert!(!cpu.flags.carry);
     }
 
     #[test]
     fn test_double_increment_wraps() {
         let mut cpu = create_test_cpu(vec![0x33]);
         cpu.stack_pointer = 0xFFFF;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.stack_pointer, 0x0);
     }
 
@@ -723,7 +1065,7 @@ mod tests {
         cpu.registers.e = 2;
         cpu.registers.h = 3;
         cpu.registers.l = 4;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.d, 3);
         assert_eq!(cpu.registers.e, 4);
         assert_eq!(cpu.registers.h, 1);
@@ -734,7 +1076,7 @@ mod tests {
     fn test_move_immediate_to_register() {
         let mut cpu = create_test_cpu(vec![0x3e, 15]);
         cpu.registers.acc = 12;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 15);
     }
 
@@ -742,7 +1084,7 @@ mod tests {
     fn test_add_immediate() {
         let mut cpu = create_test_cpu(vec![0xc6, 12]);
         cpu.registers.acc = 12;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 24);
     }
 
@@ -750,7 +1092,7 @@ mod tests {
     fn test_and_immediate() {
         let mut cpu = create_test_cpu(vec![0xe6, 0]);
         cpu.registers.acc = 0xFF;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 0);
     }
 
@@ -758,7 +1100,7 @@ mod tests {
     fn test_immediate_comparison() {
         let mut cpu = create_test_cpu(vec![0x3e, 15]);
         cpu.registers.acc = 12;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 15);
     }
 
@@ -767,7 +1109,7 @@ mod tests {
         let mut cpu = create_test_cpu(vec![0x32, 3, 0b0, 15]);
         cpu.registers.acc = 0;
         assert_eq!(cpu.memory.fetch_byte_at_offset(3), 15);
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.memory.fetch_byte_at_offset(3), 0);
     }
 
@@ -775,22 +1117,22 @@ mod tests {
     fn test_load_direct() {
         let mut cpu = create_test_cpu(vec![0x3a, 3, 0b0, 0]);
         cpu.registers.acc = 15;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.acc, 0);
     }
 
     #[test]
     fn test_jmp() {
-        let mut cpu = create_test_cpu(vec![0xc3, 3, 0b0, 0x04, 0]);
-        cpu.emulate();
+        let mut cpu = create_test_cpu(vec![0xc3, 4, 0b0, 0x04, 0]);
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.b, 0);
     }
 
     #[test]
     fn test_jnz() {
-        let mut cpu = create_test_cpu(vec![0xc2, 3, 0b0, 0x04, 0]);
+        let mut cpu = create_test_cpu(vec![0xc2, 4, 0b0, 0x04, 0]);
         cpu.flags.zero = false;
-        cpu.emulate();
+        cpu.emulate().unwrap();
         assert_eq!(cpu.registers.b, 0);
     }
 
@@ -798,7 +1140,990 @@ mod tests {
     fn test_jnz_not_set() {
         let mut cpu = create_test_cpu(vec![0xc2, 3, 0b0, 0x04, 0]);
         cpu.flags.zero = true;
-        cpu.emulate();
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.b, 1);
+    }
+
+    #[test]
+    fn test_ret() {
+        let mut cpu = create_test_cpu(vec![0xc9, 0, 0, 0, 0x34, 0x12]);
+        cpu.stack_pointer = 4;
+        execute_next(&mut cpu);
+        assert_eq!(cpu.program_counter, 0x1234);
+        assert_eq!(cpu.stack_pointer, 6);
+    }
+
+    #[test]
+    fn test_conditional_ret_taken() {
+        for condition in 0..8 {
+            let mut cpu = create_test_cpu(vec![0xc0 | (condition << 3), 0, 0, 0, 0x34, 0x12]);
+            cpu.stack_pointer = 4;
+            set_condition(&mut cpu.flags, condition, true);
+            execute_next(&mut cpu);
+            assert_eq!(cpu.program_counter, 0x1234, "condition {:#05b}", condition);
+            assert_eq!(cpu.stack_pointer, 6, "condition {:#05b}", condition);
+        }
+    }
+
+    #[test]
+    fn test_conditional_ret_not_taken() {
+        for condition in 0..8 {
+            let mut cpu = create_test_cpu(vec![0xc0 | (condition << 3), 0, 0, 0, 0x34, 0x12]);
+            cpu.stack_pointer = 4;
+            set_condition(&mut cpu.flags, condition, false);
+            execute_next(&mut cpu);
+            assert_eq!(cpu.program_counter, 1, "condition {:#05b}", condition);
+            assert_eq!(cpu.stack_pointer, 4, "condition {:#05b}", condition);
+        }
+    }
+
+    #[test]
+    fn test_call() {
+        let mut input = vec![0; 0x200];
+        input[0x0123] = 0xcd;
+        input[0x0124] = 0x80;
+        input[0x0125] = 0x01;
+        let mut cpu = create_test_cpu(input);
+        cpu.program_counter = 0x0123;
+        cpu.stack_pointer = 0x0200;
+        execute_next(&mut cpu);
+        assert_eq!(cpu.program_counter, 0x0180);
+        assert_eq!(cpu.stack_pointer, 0x01fe);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x01ff), 0x01);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x01fe), 0x26);
+    }
+
+    #[test]
+    fn test_call_and_ret() {
+        let mut cpu = create_test_cpu(vec![
+            0xcd, 0x07, 0x00, // CALL 0x0007
+            0x04,             // INR B
+            0xc3, 0x0c, 0x00, // JMP 0x000c
+            0x0c,             // INR C
+            0xc9,             // RET
+            0x00, 0x00, 0x00,
+        ]);
+        cpu.stack_pointer = 0x000c;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.b, 1);
+        assert_eq!(cpu.registers.c, 1);
+        assert_eq!(cpu.stack_pointer, 0x000c);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x000b), 0x00);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x000a), 0x03);
+    }
+
+    #[test]
+    fn test_conditional_call_taken() {
+        for condition in 0..8 {
+            let mut cpu = create_test_cpu(vec![0xc4 | (condition << 3), 0x34, 0x12, 0, 0, 0]);
+            cpu.stack_pointer = 6;
+            set_condition(&mut cpu.flags, condition, true);
+            execute_next(&mut cpu);
+            assert_eq!(cpu.program_counter, 0x1234, "condition {:#05b}", condition);
+            assert_eq!(cpu.stack_pointer, 4, "condition {:#05b}", condition);
+            assert_eq!(cpu.memory.fetch_byte_at_offset(5), 0x00, "condition {:#05b}", condition);
+            assert_eq!(cpu.memory.fetch_byte_at_offset(4), 0x03, "condition {:#05b}", condition);
+        }
+    }
+
+    #[test]
+    fn test_conditional_call_not_taken() {
+        for condition in 0..8 {
+            let mut cpu = create_test_cpu(vec![0xc4 | (condition << 3), 0x34, 0x12, 0, 0, 0]);
+            cpu.stack_pointer = 6;
+            set_condition(&mut cpu.flags, condition, false);
+            execute_next(&mut cpu);
+            assert_eq!(cpu.program_counter, 3, "condition {:#05b}", condition);
+            assert_eq!(cpu.stack_pointer, 6, "condition {:#05b}", condition);
+        }
+    }
+
+    #[test]
+    fn test_conditional_call_and_ret() {
+        for condition in 0..8 {
+            let mut cpu = create_test_cpu(vec![
+                0xc4 | (condition << 3), 0x04, 0x00, // Ccc 0x0004
+                0x00,                                // NOP
+                0xc0 | (condition << 3),             // Rcc
+                0x00, 0x00,
+            ]);
+            cpu.stack_pointer = 7;
+            set_condition(&mut cpu.flags, condition, true);
+            execute_next(&mut cpu);
+            execute_next(&mut cpu);
+            assert_eq!(cpu.program_counter, 3, "condition {:#05b}", condition);
+            assert_eq!(cpu.stack_pointer, 7, "condition {:#05b}", condition);
+        }
+    }
+
+    #[test]
+    fn test_restart() {
+        for vector in RestartVector::ALL.iter() {
+            let mut input = vec![0; 0x200];
+            input[0x0123] = vector.op_code();
+            let mut cpu = create_test_cpu(input);
+            cpu.program_counter = 0x0123;
+            cpu.stack_pointer = 0x0200;
+            execute_next(&mut cpu);
+            assert_eq!(cpu.program_counter, vector.address());
+            assert_eq!(cpu.stack_pointer, 0x01fe);
+            assert_eq!(cpu.memory.fetch_byte_at_offset(0x01ff), 0x01);
+            assert_eq!(cpu.memory.fetch_byte_at_offset(0x01fe), 0x24);
+        }
+    }
+
+    #[test]
+    fn test_restart_and_ret() {
+        let mut input = vec![0; 0x200];
+        input[0x0010] = 0x04; // INR B
+        input[0x0011] = 0xc9; // RET
+        input[0x0100] = 0xd7; // RST 2
+        let mut cpu = create_test_cpu(input);
+        cpu.program_counter = 0x0100;
+        cpu.stack_pointer = 0x0200;
+        execute_next(&mut cpu);
+        execute_next(&mut cpu);
+        execute_next(&mut cpu);
+        assert_eq!(cpu.registers.b, 1);
+        assert_eq!(cpu.program_counter, 0x0101);
+        assert_eq!(cpu.stack_pointer, 0x0200);
+    }
+
+    #[test]
+    fn test_enable_interrupts_after_next_instruction() {
+        let mut cpu = create_test_cpu(vec![0xfb, 0x00, 0x00, 0x00]);
+        cpu.stack_pointer = 4;
+        execute_next(&mut cpu);
+        assert!(cpu.interrupts_enabled());
+        assert!(!cpu.request_interrupt(RestartVector::Rst1.op_code()).unwrap());
+        execute_next(&mut cpu);
+        assert!(cpu.request_interrupt(RestartVector::Rst1.op_code()).unwrap());
+    }
+
+    #[test]
+    fn test_disable_interrupts() {
+        let mut cpu = create_test_cpu(vec![0xfb, 0x00, 0xf3, 0x00]);
+        cpu.emulate().unwrap();
+        assert!(!cpu.interrupts_enabled());
+        assert!(!cpu.request_interrupt(RestartVector::Rst1.op_code()).unwrap());
+        assert_eq!(cpu.program_counter, 4);
+    }
+
+    #[test]
+    fn test_interrupt_executes_restart() {
+        let mut input = vec![0; 0x200];
+        input[0x0100] = 0xfb;
+        let mut cpu = create_test_cpu(input);
+        cpu.program_counter = 0x0100;
+        cpu.stack_pointer = 0x0200;
+        execute_next(&mut cpu);
+        execute_next(&mut cpu);
+        assert!(cpu.request_interrupt(RestartVector::Rst1.op_code()).unwrap());
+        assert_eq!(cpu.program_counter, 0x0008);
+        assert_eq!(cpu.stack_pointer, 0x01fe);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x01ff), 0x01);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x01fe), 0x02);
+        assert!(!cpu.interrupts_enabled());
+    }
+
+    #[test]
+    fn test_interrupt_fault_leaves_cpu_unchanged() {
+        let memory = Memory::builder()
+            .read_only(0x0100..=0x01ff)
+            .rom_write_policy(RomWritePolicy::Error)
+            .build();
+        let mut cpu = Cpu::new(memory);
+        cpu.memory.load_image(0x0000, &[0xfb, 0x76]).unwrap();
+        cpu.stack_pointer = 0x0200;
+        cpu.emulate().unwrap();
+        let error = CpuError::RomWrite { program_counter: 0x0002, op_code: 0xcf, address: 0x01fe };
+        assert_eq!(cpu.request_interrupt(RestartVector::Rst1.op_code()), Err(error));
+        assert_eq!(cpu.program_counter, 0x0002);
+        assert_eq!(cpu.stack_pointer, 0x0200);
+        assert_eq!(cpu.cycles(), 4 + 7);
+        assert!(cpu.interrupts_enabled());
+        assert!(cpu.is_halted());
+    }
+
+    #[test]
+    fn test_interrupt_executes_supplied_instruction() {
+        let mut cpu = create_test_cpu(vec![0xfb, 0x76, 0x00, 0x00]);
+        cpu.stack_pointer = 4;
+        cpu.emulate().unwrap();
+        assert!(cpu.request_interrupt(0x00).unwrap());
+        assert_eq!(cpu.program_counter, 0x0002);
+        assert_eq!(cpu.stack_pointer, 4);
+        assert_eq!(cpu.cycles(), 4 + 7 + 4);
+        assert!(!cpu.is_halted());
+        assert!(!cpu.interrupts_enabled());
+    }
+
+    #[test]
+    fn test_interrupt_executes_call() {
+        let mut cpu = create_test_cpu(vec![0xfb, 0x00, 0x00, 0x00]);
+        cpu.stack_pointer = 4;
+        execute_next(&mut cpu);
+        execute_next(&mut cpu);
+        assert!(cpu.request_interrupt_with_operand(0xcd, 0x1234).unwrap());
+        assert_eq!(cpu.program_counter, 0x1234);
+        assert_eq!(cpu.stack_pointer, 2);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(2), 0x02);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(3), 0x00);
+        assert_eq!(cpu.cycles(), 4 + 4 + 17);
+    }
+
+    #[test]
+    fn test_interrupt_wakes_halt() {
+        let mut input = vec![0; 0x200];
+        input[0x0000] = 0xfb;
+        input[0x0001] = 0x76;
+        let mut cpu = create_test_cpu(input);
+        cpu.stack_pointer = 0x0200;
+        cpu.emulate().unwrap();
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.program_counter, 0x0002);
+        assert!(cpu.request_interrupt(RestartVector::Rst2.op_code()).unwrap());
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.program_counter, 0x0010);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x01fe), 0x02);
+    }
+
+    #[test]
+    fn test_input() {
+        let bus = RecordingIoBus::new();
+        bus.set_input(0x12, 0x34);
+        let mut cpu = Cpu::with_io_bus(Memory::with_image(0, &[0xdb, 0x12]).unwrap(), Box::new(bus.clone()));
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0x34);
+        assert_eq!(cpu.program_counter, 2);
+        assert_eq!(bus.reads(), vec![0x12]);
+    }
+
+    #[test]
+    fn test_output() {
+        let bus = RecordingIoBus::new();
+        let mut cpu = Cpu::with_io_bus(Memory::with_image(0, &[0xd3, 0x12]).unwrap(), Box::new(bus.clone()));
+        cpu.registers.acc = 0x56;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.program_counter, 2);
+        assert_eq!(bus.writes(), vec![(0x12, 0x56)]);
+    }
+
+    #[test]
+    fn test_input_without_devices() {
+        let mut cpu = create_test_cpu(vec![0xdb, 0x12]);
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0xFF);
+    }
+
+    #[test]
+    fn test_halt_stops_emulation() {
+        let mut cpu = create_test_cpu(vec![0x04, 0x76, 0x04]);
+        assert_eq!(cpu.emulate(), Ok(EmulateOutcome::Halted));
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.registers.b, 1);
+        assert_eq!(cpu.program_counter, 2);
+    }
+
+    #[test]
+    fn test_halt_with_interrupts_disabled() {
+        let mut cpu = create_test_cpu(vec![0x76, 0x04]);
+        cpu.emulate().unwrap();
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.registers.b, 0);
+    }
+
+    #[test]
+    fn test_halt_with_interrupts_enabled() {
+        let mut input = vec![0; 0x20];
+        input[..3].copy_from_slice(&[0xfb, 0x76, 0x04]);
+        input[0x08] = 0xc9;
+        let mut cpu = create_test_cpu(input);
+        cpu.stack_pointer = 0x20;
+        cpu.emulate().unwrap();
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.program_counter, 2);
+        assert!(cpu.request_interrupt(RestartVector::Rst1.op_code()).unwrap());
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.b, 1);
+    }
+
+    #[test]
+    fn test_resume_after_halt() {
+        let mut input = vec![0; 0x20];
+        input[..6].copy_from_slice(&[
+            0xfb,             // EI
+            0x76,             // HLT
+            0x04,             // INR B
+            0xc3, 0x20, 0x00, // JMP 0x0020
+        ]);
+        input[0x10] = 0xc9;   // RET
+        let mut cpu = create_test_cpu(input);
+        cpu.stack_pointer = 0x20;
+        cpu.emulate().unwrap();
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.registers.b, 0);
+        assert!(cpu.request_interrupt(RestartVector::Rst2.op_code()).unwrap());
+        cpu.emulate().unwrap();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.registers.b, 1);
+        assert_eq!(cpu.stack_pointer, 0x20);
+    }
+
+    #[test]
+    fn test_add_aux_carry() {
+        let mut cpu = create_test_cpu(vec![0x80]);
+        cpu.registers.acc = 0x2e;
+        cpu.registers.b = 0x74;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0xa2);
+        assert!(cpu.flags.aux_carry);
+        assert!(!cpu.flags.carry);
+    }
+
+    #[test]
+    fn test_add_with_carry_aux_carry() {
+        let mut cpu = create_test_cpu(vec![0x88]);
+        cpu.registers.acc = 0x0f;
+        cpu.registers.b = 0x00;
+        cpu.flags.carry = true;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0x10);
+        assert!(cpu.flags.aux_carry);
+    }
+
+    #[test]
+    fn test_add_immediate_with_carry() {
+        let mut cpu = create_test_cpu(vec![0xce, 0x01]);
+        cpu.registers.acc = 0x0e;
+        cpu.flags.carry = false;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0x0f);
+        assert!(!cpu.flags.aux_carry);
+    }
+
+    #[test]
+    fn test_subtract_aux_carry() {
+        let mut cpu = create_test_cpu(vec![0x97]);
+        cpu.registers.acc = 0x3e;
+        cpu.emulate().unwrap();
+        assert!(cpu.flags.aux_carry);
+
+        let mut cpu = create_test_cpu(vec![0x90]);
+        cpu.registers.acc = 0x10;
+        cpu.registers.b = 0x01;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0x0f);
+        assert!(!cpu.flags.aux_carry);
+    }
+
+    #[test]
+    fn test_subtract_immediate_with_borrow() {
+        let mut cpu = create_test_cpu(vec![0xde, 0x01]);
+        cpu.registers.acc = 0x12;
+        cpu.flags.carry = true;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0x10);
+        assert!(cpu.flags.aux_carry);
+        assert!(!cpu.flags.carry);
+    }
+
+    #[test]
+    fn test_compare_flags() {
+        let mut cpu = create_test_cpu(vec![0xb8]);
+        cpu.registers.acc = 0x02;
+        cpu.registers.b = 0x05;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0x02);
+        assert!(cpu.flags.carry);
+        assert!(!cpu.flags.zero);
+        assert!(!cpu.flags.aux_carry);
+
+        let mut cpu = create_test_cpu(vec![0xfe, 0x05]);
+        cpu.registers.acc = 0x05;
+        cpu.emulate().unwrap();
+        assert!(!cpu.flags.carry);
+        assert!(cpu.flags.zero);
+        assert!(cpu.flags.aux_carry);
+    }
+
+    #[test]
+    fn test_increment_aux_carry() {
+        let mut cpu = create_test_cpu(vec![0x04]);
+        cpu.registers.b = 0x0f;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.b, 0x10);
+        assert!(cpu.flags.aux_carry);
+    }
+
+    #[test]
+    fn test_decrement_aux_carry() {
+        let mut cpu = create_test_cpu(vec![0x05]);
+        cpu.registers.b = 0x10;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.b, 0x0f);
+        assert!(!cpu.flags.aux_carry);
+
+        let mut cpu = create_test_cpu(vec![0x05]);
+        cpu.registers.b = 0x11;
+        cpu.emulate().unwrap();
+        assert!(cpu.flags.aux_carry);
+    }
+
+    #[test]
+    fn test_logical_and_aux_carry() {
+        let mut cpu = create_test_cpu(vec![0xa0]);
+        cpu.registers.acc = 0b00001000;
+        cpu.registers.b = 0b00000001;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0);
+        assert!(cpu.flags.aux_carry);
+
+        let mut cpu = create_test_cpu(vec![0xe6, 0b11110111]);
+        cpu.registers.acc = 0b11110111;
+        cpu.emulate().unwrap();
+        assert!(!cpu.flags.aux_carry);
+    }
+
+    #[test]
+    fn test_logical_or_and_xor_clear_aux_carry() {
+        let mut cpu = create_test_cpu(vec![0xb0, 0xa8]);
+        cpu.registers.acc = 0b00001000;
+        cpu.registers.b = 0b00001000;
+        cpu.flags.aux_carry = true;
+        cpu.flags.carry = true;
+        execute_next(&mut cpu);
+        assert!(!cpu.flags.aux_carry);
+        assert!(!cpu.flags.carry);
+        cpu.flags.aux_carry = true;
+        execute_next(&mut cpu);
+        assert!(!cpu.flags.aux_carry);
+    }
+
+    #[test]
+    fn test_push_and_pop_flags() {
+        let mut cpu = create_test_cpu(vec![0xf5, 0xf1, 0x00, 0x00]);
+        cpu.stack_pointer = 4;
+        cpu.registers.acc = 0x42;
+        cpu.flags.aux_carry = true;
+        cpu.flags.parity = true;
+        execute_next(&mut cpu);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(2), 0b00010110);
+        cpu.registers.acc = 0;
+        cpu.flags = Flags::new();
+        execute_next(&mut cpu);
+        assert_eq!(cpu.registers.acc, 0x42);
+        assert!(cpu.flags.aux_carry);
+        assert!(cpu.flags.parity);
+        assert!(!cpu.flags.sign);
+        assert!(!cpu.flags.zero);
+        assert!(!cpu.flags.carry);
+        assert_eq!(cpu.stack_pointer, 4);
+    }
+
+    #[test]
+    fn test_decimal_adjust() {
+        let mut cpu = create_test_cpu(vec![0x27]);
+        cpu.registers.acc = 0x9b;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0x01);
+        assert!(cpu.flags.carry);
+        assert!(cpu.flags.aux_carry);
+    }
+
+    #[test]
+    fn test_decimal_adjust_after_add() {
+        let mut cpu = create_test_cpu(vec![0xc6, 0x38, 0x27]);
+        cpu.registers.acc = 0x29;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0x67);
+        assert!(!cpu.flags.carry);
+    }
+
+    #[test]
+    fn test_decimal_adjust_bcd_addition() {
+        let bcd = |value: u32| (((value / 10) << 4) | (value % 10)) as u8;
+        // ACI second; DAA
+        let mut cpu = create_test_cpu(vec![0xce, 0x00, 0x27]);
+        for first in 0..100 {
+            for second in 0..100 {
+                for carry in [false, true] {
+                    cpu.memory.set_byte_at_offset(1, bcd(second)).unwrap();
+                    cpu.program_counter = 0;
+                    cpu.registers.acc = bcd(first);
+                    cpu.flags.carry = carry;
+                    execute_next(&mut cpu);
+                    execute_next(&mut cpu);
+
+                    let sum = first + second + carry as u32;
+                    let case = format!("{} + {} + {}", first, second, carry as u8);
+                    assert_eq!(cpu.registers.acc, bcd(sum % 100), "{}", case);
+                    assert_eq!(cpu.flags.carry, sum >= 100, "{}", case);
+                    assert_eq!(cpu.flags.zero, sum.is_multiple_of(100), "{}", case);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_decimal_adjust_vectors() {
+        // Accumulator and flags left by an addition, and what DAA makes of them
+        let vectors = [
+            // 8080 manual example
+            (0x9b, false, false, 0x01, true, true),
+            // 09 + 09
+            (0x12, false, true, 0x18, false, false),
+            // 99 + 99
+            (0x32, true, true, 0x98, true, false),
+            // 80 + 80
+            (0x00, true, false, 0x60, true, false),
+            // 05 + 05
+            (0x0a, false, false, 0x10, false, true),
+            // 45 + 55
+            (0x9a, false, false, 0x00, true, true),
+        ];
+        for &(acc, carry, aux_carry, expected, expected_carry, expected_aux_carry) in vectors.iter() {
+            let mut cpu = create_test_cpu(vec![0x27]);
+            cpu.registers.acc = acc;
+            cpu.flags.carry = carry;
+            cpu.flags.aux_carry = aux_carry;
+            execute_next(&mut cpu);
+            let case = format!("acc {:#04x}, carry {}, aux carry {}", acc, carry, aux_carry);
+            assert_eq!(cpu.registers.acc, expected, "{}", case);
+            assert_eq!(cpu.flags.carry, expected_carry, "{}", case);
+            assert_eq!(cpu.flags.aux_carry, expected_aux_carry, "{}", case);
+        }
+    }
+
+    #[test]
+    fn test_load_register_pair_immediate() {
+        let mut cpu = create_test_cpu(vec![
+            0x01, 0x34, 0x12,
+            0x11, 0x78, 0x56,
+            0x21, 0xbc, 0x9a,
+            0x31, 0xf0, 0xde,
+        ]);
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.get_bc(), 0x1234);
+        assert_eq!(cpu.registers.get_de(), 0x5678);
+        assert_eq!(cpu.registers.get_hl(), 0x9abc);
+        assert_eq!(cpu.stack_pointer, 0xdef0);
+        assert_eq!(cpu.program_counter, 12);
+    }
+
+    #[test]
+    fn test_store_hl_direct() {
+        let mut cpu = create_test_cpu(vec![0x22, 0x04, 0x00, 0x00, 0x00, 0x00]);
+        cpu.registers.h = 0xae;
+        cpu.registers.l = 0x29;
+        execute_next(&mut cpu);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(4), 0x29);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(5), 0xae);
+        assert_eq!(cpu.program_counter, 3);
+    }
+
+    #[test]
+    fn test_load_hl_direct() {
+        let mut cpu = create_test_cpu(vec![0x2a, 0x04, 0x00, 0x00, 0x29, 0xae]);
+        execute_next(&mut cpu);
+        assert_eq!(cpu.registers.l, 0x29);
+        assert_eq!(cpu.registers.h, 0xae);
+        assert_eq!(cpu.program_counter, 3);
+    }
+
+    #[test]
+    fn test_store_accumulator() {
+        let mut cpu = create_test_cpu(vec![0x02, 0x12, 0x00, 0x00]);
+        cpu.registers.acc = 0x42;
+        cpu.registers.set_bc(0x0002);
+        cpu.registers.set_de(0x0003);
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.memory.fetch_byte_at_offset(2), 0x42);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(3), 0x42);
+    }
+
+    #[test]
+    fn test_load_accumulator() {
+        let mut cpu = create_test_cpu(vec![0x0a, 0x00, 0x1a, 0x00, 0x42, 0x43]);
+        cpu.registers.set_bc(0x0004);
+        cpu.registers.set_de(0x0005);
+        execute_next(&mut cpu);
+        assert_eq!(cpu.registers.acc, 0x42);
+        cpu.program_counter = 2;
+        execute_next(&mut cpu);
+        assert_eq!(cpu.registers.acc, 0x43);
+    }
+
+    #[test]
+    fn test_decrement_double() {
+        let mut cpu = create_test_cpu(vec![0x0b, 0x1b, 0x2b, 0x3b]);
+        cpu.registers.set_bc(0x9800);
+        cpu.registers.set_de(0x0001);
+        cpu.registers.set_hl(0x0000);
+        cpu.stack_pointer = 0x1234;
+        cpu.flags.zero = false;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.get_bc(), 0x97ff);
+        assert_eq!(cpu.registers.get_de(), 0x0000);
+        assert_eq!(cpu.registers.get_hl(), 0xffff);
+        assert_eq!(cpu.stack_pointer, 0x1233);
+        assert!(!cpu.flags.zero);
+    }
+
+    #[test]
+    fn test_load_sp_from_hl() {
+        let mut cpu = create_test_cpu(vec![0xf9]);
+        cpu.registers.set_hl(0x506c);
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.stack_pointer, 0x506c);
+    }
+
+    #[test]
+    fn test_exchange_stack_top() {
+        let mut cpu = create_test_cpu(vec![0xe3, 0x00, 0xf0, 0x0d]);
+        cpu.stack_pointer = 2;
+        cpu.registers.h = 0x0b;
+        cpu.registers.l = 0x3c;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.h, 0x0d);
+        assert_eq!(cpu.registers.l, 0xf0);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(2), 0x3c);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(3), 0x0b);
+        assert_eq!(cpu.stack_pointer, 2);
+    }
+
+    #[test]
+    fn test_load_pc_from_hl() {
+        let mut cpu = create_test_cpu(vec![0xe9]);
+        cpu.registers.set_hl(0x413e);
+        execute_next(&mut cpu);
+        assert_eq!(cpu.program_counter, 0x413e);
+    }
+
+    #[test]
+    fn test_count_cycles() {
+        let mut cpu = create_test_cpu(vec![0x3e, 0x01, 0x80, 0x77, 0x00]);
+        cpu.registers.set_hl(0x0000);
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.cycles(), 7 + 4 + 7 + 4);
+    }
+
+    #[test]
+    fn test_conditional_call_cycles() {
+        let mut cpu = create_test_cpu(vec![0xc4, 0x00, 0x00, 0x00, 0x00]);
+        cpu.stack_pointer = 5;
+        cpu.flags.zero = true;
+        execute_next(&mut cpu);
+        assert_eq!(cpu.cycles(), 11);
+
+        let mut cpu = create_test_cpu(vec![0xc4, 0x00, 0x00, 0x00, 0x00]);
+        cpu.stack_pointer = 5;
+        cpu.flags.zero = false;
+        execute_next(&mut cpu);
+        assert_eq!(cpu.cycles(), 17);
+    }
+
+    #[test]
+    fn test_conditional_return_cycles() {
+        let mut cpu = create_test_cpu(vec![0xc0, 0x00, 0x00]);
+        cpu.stack_pointer = 1;
+        cpu.flags.zero = true;
+        execute_next(&mut cpu);
+        assert_eq!(cpu.cycles(), 5);
+
+        let mut cpu = create_test_cpu(vec![0xc0, 0x00, 0x00]);
+        cpu.stack_pointer = 1;
+        cpu.flags.zero = false;
+        execute_next(&mut cpu);
+        assert_eq!(cpu.cycles(), 11);
+    }
+
+    #[test]
+    fn test_interrupt_cycles() {
+        let mut cpu = create_test_cpu(vec![0xfb, 0x00, 0x00, 0x00]);
+        cpu.stack_pointer = 4;
+        cpu.emulate().unwrap();
+        assert!(cpu.request_interrupt(RestartVector::Rst0.op_code()).unwrap());
+        assert_eq!(cpu.cycles(), 4 + 4 + 4 + 4 + 11);
+    }
+
+    #[test]
+    fn test_run_for_cycles() {
+        let mut cpu = create_test_cpu(vec![0xc3, 0x00, 0x00]);
+        assert_eq!(cpu.run_for_cycles(25, HaltMode::Stop).unwrap(), 30);
+        assert_eq!(cpu.cycles(), 30);
+        assert_eq!(cpu.run_for_cycles(10, HaltMode::Stop).unwrap(), 10);
+        assert_eq!(cpu.cycles(), 40);
+    }
+
+    #[test]
+    fn test_run_for_cycles_saturates() {
+        let mut cpu = create_test_cpu(vec![0x00, 0x76]);
+        cpu.run_for_cycles(4, HaltMode::Stop).unwrap();
+        assert_eq!(cpu.run_for_cycles(u64::MAX, HaltMode::Stop).unwrap(), 7);
+        assert!(cpu.is_halted());
+    }
+
+    #[test]
+    fn test_run_for_cycles_stops_on_halt() {
+        let mut cpu = create_test_cpu(vec![0x00, 0x76]);
+        assert_eq!(cpu.run_for_cycles(100, HaltMode::Stop).unwrap(), 11);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.run_for_cycles(100, HaltMode::Stop).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_run_for_cycles_waits_on_halt() {
+        let mut cpu = create_test_cpu(vec![0xfb, 0x76, 0x00, 0x00, 0x00]);
+        cpu.stack_pointer = 5;
+        assert_eq!(cpu.run_for_cycles(100, HaltMode::WaitForInterrupt).unwrap(), 100);
+        assert!(cpu.is_halted());
+        assert!(cpu.request_interrupt(RestartVector::Rst0.op_code()).unwrap());
+        assert_eq!(cpu.cycles(), 111);
+        assert!(!cpu.is_halted());
+    }
+
+    #[test]
+    fn test_step() {
+        let mut cpu = create_test_cpu(vec![0x06, 0x12, 0x76]);
+        assert_eq!(cpu.step(), Ok(StepOutcome::Executed(ExecutedInstruction {
+            op_code: 0x06,
+            instruction: Instruction::Mvi(Register::B),
+            operand: Some(0x12),
+            program_counter_before: 0,
+            program_counter_after: 2,
+            cycles: 7,
+        })));
+        assert_eq!(cpu.registers.b, 0x12);
+        assert_eq!(cpu.step(), Ok(StepOutcome::Executed(ExecutedInstruction {
+            op_code: 0x76,
+            instruction: Instruction::Hlt,
+            operand: None,
+            program_counter_before: 2,
+            program_counter_after: 3,
+            cycles: 7,
+        })));
+        assert_eq!(cpu.step(), Ok(StepOutcome::Halted));
+        assert_eq!(cpu.program_counter, 3);
+    }
+
+    #[test]
+    fn test_undocumented_op_codes() {
+        let mut input = vec![0; 0x30];
+        input[..7].copy_from_slice(&[
+            0x08,             // NOP
+            0xdd, 0x10, 0x00, // CALL 0x0010
+            0xcb, 0x20, 0x00, // JMP 0x0020
+        ]);
+        input[0x10] = 0x04; // INR B
+        input[0x11] = 0xd9; // RET
+        input[0x20] = 0x76; // HLT
+        let mut cpu = create_test_cpu(input);
+        cpu.stack_pointer = 0x30;
+        cpu.emulate().unwrap();
+        assert!(cpu.is_halted());
         assert_eq!(cpu.registers.b, 1);
+        assert_eq!(cpu.program_counter, 0x21);
+        assert_eq!(cpu.stack_pointer, 0x30);
+        assert_eq!(cpu.cycles(), 4 + 17 + 5 + 10 + 10 + 7);
+    }
+
+    #[test]
+    fn test_stack_wraps_around() {
+        let mut cpu = create_test_cpu(vec![0xc5, 0xd1]);
+        cpu.stack_pointer = 1;
+        cpu.registers.set_bc(0x1234);
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0xFFFF), 0x34);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x0000), 0x12);
+        assert_eq!(cpu.stack_pointer, 1);
+        assert_eq!(cpu.registers.get_de(), 0x1234);
+    }
+
+    #[test]
+    fn test_word_access_wraps_around() {
+        let mut cpu = create_test_cpu(vec![0x22, 0xFF, 0xFF]);
+        cpu.registers.set_hl(0xABCD);
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0xFFFF), 0xCD);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x0000), 0xAB);
+    }
+
+    #[test]
+    fn test_program_counter_wraps_around() {
+        let mut cpu = create_test_cpu(vec![]);
+        cpu.memory.load_image(0xFFFE, &[0xc3, 0x34]).unwrap();
+        cpu.memory.set_byte_at_offset(0x0000, 0x12).unwrap();
+        cpu.program_counter = 0xFFFE;
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x1234);
+
+        cpu.memory.set_byte_at_offset(0xFFFF, 0x00).unwrap();
+        cpu.program_counter = 0xFFFF;
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0000);
+    }
+
+    #[test]
+    fn test_emulate_stops_outside_images() {
+        let mut cpu = create_test_cpu(vec![0x00, 0x00]);
+        assert_eq!(cpu.emulate(), Ok(EmulateOutcome::LeftImage { program_counter: 2 }));
+        assert_eq!(cpu.program_counter, 2);
+
+        let mut cpu = Cpu::new(Memory::new());
+        assert_eq!(cpu.emulate(), Ok(EmulateOutcome::LeftImage { program_counter: 0 }));
+
+        let mut cpu = Cpu::new(Memory::with_image(0x0100, &[0xff]).unwrap());
+        cpu.program_counter = 0x0100;
+        cpu.stack_pointer = 0x0200;
+        assert_eq!(cpu.emulate(), Ok(EmulateOutcome::LeftImage { program_counter: 0x0038 }));
+        assert_eq!(cpu.stack_pointer, 0x01fe);
+    }
+
+    #[test]
+    fn test_rom_write() {
+        let memory = Memory::builder()
+            .read_only(0x0000..=0x0FFF)
+            .rom_write_policy(RomWritePolicy::Error)
+            .build();
+        let mut cpu = Cpu::new(memory);
+        cpu.memory.load_image(0x0000, &[0x32, 0x00, 0x10, 0x32, 0x00, 0x00]).unwrap();
+        cpu.registers.acc = 0x12;
+        cpu.step().unwrap();
+        let error = CpuError::RomWrite { program_counter: 3, op_code: 0x32, address: 0x0000 };
+        assert_eq!(cpu.step(), Err(error));
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x1000), 0x12);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x0000), 0x32);
+        assert_eq!(error.to_string(), "write to ROM at 0x0000 from 0x0003 (op code 0x32)");
+    }
+
+    #[test]
+    fn test_rom_write_leaves_cpu_unchanged() {
+        let memory = Memory::builder()
+            .read_only(0x1000..=0x1FFF)
+            .rom_write_policy(RomWritePolicy::Error)
+            .build();
+        let mut cpu = Cpu::new(memory);
+        cpu.memory.load_image(0x0000, &[0xcd, 0x00, 0x02, 0x22, 0xFF, 0x0F, 0x34]).unwrap();
+        cpu.stack_pointer = 0x1002;
+        let error = CpuError::RomWrite { program_counter: 0, op_code: 0xcd, address: 0x1000 };
+        assert_eq!(cpu.step(), Err(error));
+        assert_eq!(cpu.step(), Err(error));
+        assert_eq!(cpu.program_counter, 0);
+        assert_eq!(cpu.stack_pointer, 0x1002);
+        assert_eq!(cpu.cycles(), 0);
+
+        cpu.program_counter = 3;
+        cpu.registers.set_hl(0x1234);
+        let error = CpuError::RomWrite { program_counter: 3, op_code: 0x22, address: 0x1000 };
+        assert_eq!(cpu.step(), Err(error));
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x0FFF), 0x00);
+        assert_eq!(cpu.program_counter, 3);
+
+        cpu.program_counter = 6;
+        cpu.registers.set_hl(0x1000);
+        let flags = cpu.flags;
+        assert!(cpu.step().is_err());
+        assert_eq!(cpu.flags, flags);
+        assert_eq!(cpu.program_counter, 6);
+    }
+
+    #[derive(Debug)]
+    struct BankSelectPort {
+        selector: BankSelector,
+    }
+
+    impl IoBus for BankSelectPort {
+        fn read_port(&mut self, _port: u8) -> u8 {
+            self.selector.selected(0) as u8
+        }
+
+        fn write_port(&mut self, _port: u8, value: u8) {
+            self.selector.select(0, value as usize);
+        }
+    }
+
+    #[test]
+    fn test_bank_switch_from_io_port() {
+        let mut memory = Memory::builder().banks(2, 0x4000).build();
+        memory.load_image(0x8000, &[0x3e, 0x01, 0xd3, 0x00, 0x3a, 0x00, 0x00]).unwrap();
+        memory.load_image_into_bank(1, 0x0000, &[0x12]).unwrap();
+        let selector = memory.bank_selector();
+        let mut cpu = Cpu::with_io_bus(memory, Box::new(BankSelectPort { selector }));
+        cpu.program_counter = 0x8000;
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0x12);
+    }
+
+    #[test]
+    fn test_load_intel_hex() {
+        let mut cpu = create_test_cpu(vec![]);
+        cpu.load_intel_hex(":020100003E01BE\n:0400000500000100F6\n:00000001FF\n").unwrap();
+        assert_eq!(cpu.program_counter, 0x0100);
+        cpu.emulate().unwrap();
+        assert_eq!(cpu.registers.acc, 0x01);
+
+        cpu.load_intel_hex(":0100000001FE\n:00000001FF\n").unwrap();
+        assert_eq!(cpu.program_counter, 0x0102);
+        assert!(matches!(cpu.load_intel_hex(":0100000001FE\n"), Err(LoadError::Format(FormatError::MissingEndOfFile))));
+    }
+
+    #[test]
+    fn test_load_detected() {
+        let mut cpu = create_test_cpu(vec![]);
+        cpu.load_detected(b"S1040100AA50\nS9030100FB\n", 0x0000).unwrap();
+        assert_eq!(cpu.program_counter, 0x0100);
+        cpu.load_detected(&[0x3E, 0x01], 0x0200).unwrap();
+        assert_eq!(cpu.program_counter, 0x0100);
+        assert_eq!(cpu.memory.fetch_byte_at_offset(0x0201), 0x01);
+    }
+
+    #[test]
+    fn test_step_records_jump() {
+        let mut cpu = create_test_cpu(vec![0x00, 0xc3, 0x34, 0x12]);
+        cpu.program_counter = 1;
+        let executed = match cpu.step().unwrap() {
+            StepOutcome::Executed(executed) => executed,
+            StepOutcome::Halted => panic!("CPU should not be halted"),
+        };
+        assert_eq!(executed.op_code, 0xc3);
+        assert_eq!(executed.instruction, Instruction::Jmp);
+        assert_eq!(executed.operand, Some(0x1234));
+        assert_eq!(executed.program_counter_before, 1);
+        assert_eq!(executed.program_counter_after, 0x1234);
+        assert_eq!(executed.cycles, 10);
+    }
+
+    #[test]
+    fn test_step_records_conditional_call_cycles() {
+        let mut cpu = create_test_cpu(vec![0xcc, 0x34, 0x12, 0xcc, 0x34, 0x12]);
+        cpu.stack_pointer = 6;
+        cpu.flags.zero = false;
+        let mut cycles = Vec::new();
+        for _ in 0..2 {
+            if let StepOutcome::Executed(executed) = cpu.step().unwrap() {
+                cycles.push((executed.program_counter_after, executed.cycles));
+            }
+            cpu.flags.zero = true;
+        }
+        assert_eq!(cycles, vec![(3, 11), (0x1234, 17)]);
+    }
+
+    #[test]
+    fn test_accessors() {
+        let mut cpu = create_test_cpu(vec![0x00, 0x00]);
+        cpu.set_program_counter(1);
+        cpu.set_stack_pointer(0x1234);
+        cpu.registers_mut().b = 0x56;
+        cpu.flags_mut().carry = true;
+        cpu.memory_mut().set_byte_at_offset(0, 0x78).unwrap();
+        let snapshot = *cpu.registers();
+        cpu.registers_mut().b = 0;
+        assert_eq!(cpu.program_counter(), 1);
+        assert_eq!(cpu.stack_pointer(), 0x1234);
+        assert_eq!(snapshot.b, 0x56);
+        assert_eq!(cpu.registers().b, 0);
+        assert!(cpu.flags().carry);
+        assert_eq!(cpu.memory().fetch_byte_at_offset(0), 0x78);
     }
 }
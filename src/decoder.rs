@@ -0,0 +1,613 @@
+use std::fmt::Debug;
+
+/// 8-bit operand of an instruction, `M` being the memory byte addressed by HL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    /// Register B.
+    B,
+    /// Register C.
+    C,
+    /// Register D.
+    D,
+    /// Register E.
+    E,
+    /// Register H.
+    H,
+    /// Register L.
+    L,
+    /// Memory byte addressed by HL.
+    M,
+    /// Accumulator.
+    A,
+}
+
+/// 16-bit operand of an instruction. PUSH and POP address PSW where the others address SP.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterPair {
+    /// Registers B and C, B holding the high byte.
+    BC,
+    /// Registers D and E, D holding the high byte.
+    DE,
+    /// Registers H and L, H holding the high byte.
+    HL,
+    /// Stack pointer.
+    SP,
+    /// Accumulator and flags, as pushed and popped.
+    PSW,
+}
+
+/// Flag condition of the conditional jumps, calls and returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    /// NZ: zero flag reset.
+    NotZero,
+    /// Z: zero flag set.
+    Zero,
+    /// NC: carry flag reset.
+    NoCarry,
+    /// C: carry flag set.
+    Carry,
+    /// PO: parity flag reset.
+    ParityOdd,
+    /// PE: parity flag set.
+    ParityEven,
+    /// P: sign flag reset.
+    Plus,
+    /// M: sign flag set.
+    Minus,
+}
+
+/// Operation performed on the accumulator by the ALU instruction groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOperation {
+    /// ADD / ADI.
+    Add,
+    /// ADC / ACI.
+    AddWithCarry,
+    /// SUB / SUI.
+    Subtract,
+    /// SBB / SBI.
+    SubtractWithBorrow,
+    /// ANA / ANI.
+    And,
+    /// XRA / XRI.
+    Xor,
+    /// ORA / ORI.
+    Or,
+    /// CMP / CPI, subtracting only to set the flags.
+    Compare,
+}
+
+/// Restart vector of an RST instruction, which calls the address 8 times its number.
+/// Interrupting devices place the RST op code of a vector on the data bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartVector {
+    /// RST 0, calling 0x0000.
+    Rst0,
+    /// RST 1, calling 0x0008.
+    Rst1,
+    /// RST 2, calling 0x0010.
+    Rst2,
+    /// RST 3, calling 0x0018.
+    Rst3,
+    /// RST 4, calling 0x0020.
+    Rst4,
+    /// RST 5, calling 0x0028.
+    Rst5,
+    /// RST 6, calling 0x0030.
+    Rst6,
+    /// RST 7, calling 0x0038.
+    Rst7,
+}
+
+impl RestartVector {
+    /// All vectors, in order of their number.
+    pub const ALL: [RestartVector; 8] = [
+        RestartVector::Rst0,
+        RestartVector::Rst1,
+        RestartVector::Rst2,
+        RestartVector::Rst3,
+        RestartVector::Rst4,
+        RestartVector::Rst5,
+        RestartVector::Rst6,
+        RestartVector::Rst7,
+    ];
+
+    /// Vector with the given number, `None` past 7.
+    pub fn from_number(number: u8) -> Option<RestartVector> {
+        RestartVector::ALL.get(number as usize).copied()
+    }
+
+    /// Vector called by the given op code, `None` unless it is an RST.
+    pub fn from_op_code(op_code: u8) -> Option<RestartVector> {
+        match decode(op_code) {
+            Instruction::Rst(vector) => Some(vector),
+            _ => None,
+        }
+    }
+
+    /// Number of the vector, 0 to 7.
+    pub fn number(self) -> u8 {
+        self as u8
+    }
+
+    /// Address the vector transfers control to.
+    pub fn address(self) -> u16 {
+        (self as u16) << 3
+    }
+
+    /// Op code of the RST instruction for the vector.
+    pub fn op_code(self) -> u8 {
+        0xc7 | ((self as u8) << 3)
+    }
+}
+
+/// Kind of the data following the op code in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// No operand, the instruction is the op code alone.
+    None,
+    /// A byte, such as the immediate value of MVI.
+    Byte,
+    /// A little-endian word, such as the target of JMP.
+    Word,
+}
+
+/// Instruction an op code decodes to. Operands following the op code are not part of it,
+/// `operand` tells what kind there is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// NOP.
+    Nop,
+    /// LXI rp: loads the pair with the operand.
+    Lxi(RegisterPair),
+    /// STAX rp: stores A at the address in BC or DE.
+    Stax(RegisterPair),
+    /// SHLD: stores L and H at the operand address.
+    Shld,
+    /// STA: stores A at the operand address.
+    Sta,
+    /// INX rp: increments the pair, leaving the flags alone.
+    Inx(RegisterPair),
+    /// INR r: increments the register, leaving the carry alone.
+    Inr(Register),
+    /// DCR r: decrements the register, leaving the carry alone.
+    Dcr(Register),
+    /// MVI r: moves the operand into the register.
+    Mvi(Register),
+    /// RLC: rotates A left, bit 7 going to the carry and bit 0.
+    Rlc,
+    /// RRC: rotates A right, bit 0 going to the carry and bit 7.
+    Rrc,
+    /// RAL: rotates A left through the carry.
+    Ral,
+    /// RAR: rotates A right through the carry.
+    Rar,
+    /// DAA: adjusts A to two BCD digits after an addition.
+    Daa,
+    /// CMA: complements A.
+    Cma,
+    /// STC: sets the carry.
+    Stc,
+    /// CMC: complements the carry.
+    Cmc,
+    /// DAD rp: adds the pair to HL, setting only the carry.
+    Dad(RegisterPair),
+    /// LDAX rp: loads A from the address in BC or DE.
+    Ldax(RegisterPair),
+    /// LHLD: loads L and H from the operand address.
+    Lhld,
+    /// LDA: loads A from the operand address.
+    Lda,
+    /// DCX rp: decrements the pair, leaving the flags alone.
+    Dcx(RegisterPair),
+    /// MOV d,s: copies the second register into the first.
+    Mov(Register, Register),
+    /// HLT: halts until an interrupt.
+    Hlt,
+    /// ADD r to CMP r: applies the operation to A and the register.
+    Alu(AluOperation, Register),
+    /// ADI to CPI: applies the operation to A and the operand.
+    AluImmediate(AluOperation),
+    /// Rcc: returns if the condition holds.
+    Rcc(Condition),
+    /// RET.
+    Ret,
+    /// POP rp.
+    Pop(RegisterPair),
+    /// Jcc: jumps to the operand if the condition holds.
+    Jcc(Condition),
+    /// JMP: jumps to the operand.
+    Jmp,
+    /// Ccc: calls the operand if the condition holds.
+    Ccc(Condition),
+    /// CALL: calls the operand.
+    Call,
+    /// PUSH rp.
+    Push(RegisterPair),
+    /// RST n: calls the restart vector.
+    Rst(RestartVector),
+    /// OUT: writes A to the operand port.
+    Out,
+    /// IN: reads A from the operand port.
+    In,
+    /// XTHL: exchanges HL with the word on top of the stack.
+    Xthl,
+    /// PCHL: jumps to the address in HL.
+    Pchl,
+    /// XCHG: exchanges HL with DE.
+    Xchg,
+    /// SPHL: loads SP from HL.
+    Sphl,
+    /// DI: disables interrupts.
+    Di,
+    /// EI: enables interrupts after the next instruction.
+    Ei,
+}
+
+static DECODE_TABLE: [Instruction; 256] = build_decode_table();
+
+/// Instruction executed for `op_code`. Undocumented op codes decode to the instruction
+/// the 8080 executes for them, see `is_undocumented`.
+pub fn decode(op_code: u8) -> Instruction {
+    DECODE_TABLE[op_code as usize]
+}
+
+/// Whether `op_code` is left out of the Intel documentation. These are aliases of NOP
+/// (0x08-0x38), JMP (0xcb), RET (0xd9) and CALL (0xdd, 0xed, 0xfd).
+pub fn is_undocumented(op_code: u8) -> bool {
+    matches!(op_code, 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xcb | 0xd9 | 0xdd | 0xed | 0xfd)
+}
+
+const fn build_decode_table() -> [Instruction; 256] {
+    let mut table = [Instruction::Nop; 256];
+    let mut op_code = 0;
+    while op_code < table.len() {
+        table[op_code] = decode_op_code(op_code as u8);
+        op_code += 1;
+    }
+    table
+}
+
+const fn decode_op_code(op_code: u8) -> Instruction {
+    let first_operand = (op_code & 0b00111000) >> 3;
+    let second_operand = op_code & 0b00000111;
+    let register_pair = (op_code & 0b00110000) >> 4;
+    match op_code {
+        0x00 | 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Instruction::Nop,
+        0x02 | 0x12 => Instruction::Stax(decode_register_pair(register_pair)),
+        0x0a | 0x1a => Instruction::Ldax(decode_register_pair(register_pair)),
+        0x22 => Instruction::Shld,
+        0x2a => Instruction::Lhld,
+        0x32 => Instruction::Sta,
+        0x3a => Instruction::Lda,
+        0x07 => Instruction::Rlc,
+        0x0f => Instruction::Rrc,
+        0x17 => Instruction::Ral,
+        0x1f => Instruction::Rar,
+        0x27 => Instruction::Daa,
+        0x2f => Instruction::Cma,
+        0x37 => Instruction::Stc,
+        0x3f => Instruction::Cmc,
+        0x76 => Instruction::Hlt,
+        0xc3 | 0xcb => Instruction::Jmp,
+        0xc9 | 0xd9 => Instruction::Ret,
+        0xcd | 0xdd | 0xed | 0xfd => Instruction::Call,
+        0xd3 => Instruction::Out,
+        0xdb => Instruction::In,
+        0xe3 => Instruction::Xthl,
+        0xe9 => Instruction::Pchl,
+        0xeb => Instruction::Xchg,
+        0xf3 => Instruction::Di,
+        0xf9 => Instruction::Sphl,
+        0xfb => Instruction::Ei,
+        _ if op_code < 0x40 => match op_code & 0b00001111 {
+            0b0001 => Instruction::Lxi(decode_register_pair(register_pair)),
+            0b0011 => Instruction::Inx(decode_register_pair(register_pair)),
+            0b1001 => Instruction::Dad(decode_register_pair(register_pair)),
+            0b1011 => Instruction::Dcx(decode_register_pair(register_pair)),
+            _ => match second_operand {
+                0b100 => Instruction::Inr(decode_register(first_operand)),
+                0b101 => Instruction::Dcr(decode_register(first_operand)),
+                _ => Instruction::Mvi(decode_register(first_operand)),
+            },
+        },
+        _ if op_code < 0x80 => Instruction::Mov(decode_register(first_operand), decode_register(second_operand)),
+        _ if op_code < 0xc0 => Instruction::Alu(decode_alu_operation(first_operand), decode_register(second_operand)),
+        _ => match second_operand {
+            0b000 => Instruction::Rcc(decode_condition(first_operand)),
+            0b001 => Instruction::Pop(decode_stack_register_pair(register_pair)),
+            0b010 => Instruction::Jcc(decode_condition(first_operand)),
+            0b100 => Instruction::Ccc(decode_condition(first_operand)),
+            0b101 => Instruction::Push(decode_stack_register_pair(register_pair)),
+            0b110 => Instruction::AluImmediate(decode_alu_operation(first_operand)),
+            _ => Instruction::Rst(decode_restart_vector(first_operand)),
+        },
+    }
+}
+
+const fn decode_register(encoded: u8) -> Register {
+    match encoded {
+        0b000 => Register::B,
+        0b001 => Register::C,
+        0b010 => Register::D,
+        0b011 => Register::E,
+        0b100 => Register::H,
+        0b101 => Register::L,
+        0b110 => Register::M,
+        _ => Register::A,
+    }
+}
+
+const fn decode_register_pair(encoded: u8) -> RegisterPair {
+    match encoded {
+        0b00 => RegisterPair::BC,
+        0b01 => RegisterPair::DE,
+        0b10 => RegisterPair::HL,
+        _ => RegisterPair::SP,
+    }
+}
+
+const fn decode_stack_register_pair(encoded: u8) -> RegisterPair {
+    match encoded {
+        0b00 => RegisterPair::BC,
+        0b01 => RegisterPair::DE,
+        0b10 => RegisterPair::HL,
+        _ => RegisterPair::PSW,
+    }
+}
+
+const fn decode_condition(encoded: u8) -> Condition {
+    match encoded {
+        0b000 => Condition::NotZero,
+        0b001 => Condition::Zero,
+        0b010 => Condition::NoCarry,
+        0b011 => Condition::Carry,
+        0b100 => Condition::ParityOdd,
+        0b101 => Condition::ParityEven,
+        0b110 => Condition::Plus,
+        _ => Condition::Minus,
+    }
+}
+
+const fn decode_restart_vector(encoded: u8) -> RestartVector {
+    match encoded {
+        0b000 => RestartVector::Rst0,
+        0b001 => RestartVector::Rst1,
+        0b010 => RestartVector::Rst2,
+        0b011 => RestartVector::Rst3,
+        0b100 => RestartVector::Rst4,
+        0b101 => RestartVector::Rst5,
+        0b110 => RestartVector::Rst6,
+        _ => RestartVector::Rst7,
+    }
+}
+
+const fn decode_alu_operation(encoded: u8) -> AluOperation {
+    match encoded {
+        0b000 => AluOperation::Add,
+        0b001 => AluOperation::AddWithCarry,
+        0b010 => AluOperation::Subtract,
+        0b011 => AluOperation::SubtractWithBorrow,
+        0b100 => AluOperation::And,
+        0b101 => AluOperation::Xor,
+        0b110 => AluOperation::Or,
+        _ => AluOperation::Compare,
+    }
+}
+
+impl Instruction {
+    /// Kind of the data following the op code.
+    pub fn operand(&self) -> Operand {
+        match self {
+            Instruction::Mvi(_) | Instruction::AluImmediate(_) | Instruction::In | Instruction::Out => Operand::Byte,
+            Instruction::Lxi(_) | Instruction::Shld | Instruction::Lhld | Instruction::Sta | Instruction::Lda |
+            Instruction::Jmp | Instruction::Jcc(_) | Instruction::Call | Instruction::Ccc(_) => Operand::Word,
+            _ => Operand::None,
+        }
+    }
+
+    /// Size in bytes, including the op code.
+    pub fn length(&self) -> u8 {
+        match self.operand() {
+            Operand::None => 1,
+            Operand::Byte => 2,
+            Operand::Word => 3,
+        }
+    }
+
+    /// T-states taken by the instruction. Conditional calls and returns take this
+    /// long when their condition is not met, see `cycles_when_taken`.
+    pub fn cycles(&self) -> u8 {
+        match self {
+            Instruction::Mov(Register::M, _) | Instruction::Mov(_, Register::M) => 7,
+            Instruction::Mov(_, _) => 5,
+            Instruction::Mvi(Register::M) => 10,
+            Instruction::Mvi(_) => 7,
+            Instruction::Inr(Register::M) | Instruction::Dcr(Register::M) => 10,
+            Instruction::Inr(_) | Instruction::Dcr(_) => 5,
+            Instruction::Alu(_, Register::M) => 7,
+            Instruction::Alu(_, _) => 4,
+            Instruction::AluImmediate(_) => 7,
+            Instruction::Lxi(_) => 10,
+            Instruction::Lda | Instruction::Sta => 13,
+            Instruction::Lhld | Instruction::Shld => 16,
+            Instruction::Ldax(_) | Instruction::Stax(_) => 7,
+            Instruction::Inx(_) | Instruction::Dcx(_) => 5,
+            Instruction::Dad(_) => 10,
+            Instruction::Jmp | Instruction::Jcc(_) => 10,
+            Instruction::Call => 17,
+            Instruction::Ccc(_) => 11,
+            Instruction::Ret => 10,
+            Instruction::Rcc(_) => 5,
+            Instruction::Rst(_) => 11,
+            Instruction::Push(_) => 11,
+            Instruction::Pop(_) => 10,
+            Instruction::Xthl => 18,
+            Instruction::Pchl | Instruction::Sphl => 5,
+            Instruction::In | Instruction::Out => 10,
+            Instruction::Hlt => 7,
+            Instruction::Nop | Instruction::Rlc | Instruction::Rrc | Instruction::Ral | Instruction::Rar |
+            Instruction::Daa | Instruction::Cma | Instruction::Stc | Instruction::Cmc | Instruction::Xchg |
+            Instruction::Di | Instruction::Ei => 4,
+        }
+    }
+
+    /// T-states taken by the instruction when its condition is met.
+    pub fn cycles_when_taken(&self) -> u8 {
+        match self {
+            Instruction::Ccc(_) => 17,
+            Instruction::Rcc(_) => 11,
+            _ => self.cycles(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count_matching(predicate: fn(&Instruction) -> bool) -> usize {
+        (0..=255_u8).map(decode).filter(predicate).count()
+    }
+
+    #[test]
+    fn test_decode_transfer() {
+        assert_eq!(decode(0x50), Instruction::Mov(Register::D, Register::B));
+        assert_eq!(decode(0x66), Instruction::Mov(Register::H, Register::M));
+        assert_eq!(decode(0x77), Instruction::Mov(Register::M, Register::A));
+        assert_eq!(count_matching(|i| matches!(i, Instruction::Mov(_, _))), 63);
+    }
+
+    #[test]
+    fn test_decode_halt() {
+        assert_eq!(decode(0x76), Instruction::Hlt);
+    }
+
+    #[test]
+    fn test_decode_alu() {
+        assert_eq!(decode(0x81), Instruction::Alu(AluOperation::Add, Register::C));
+        assert_eq!(decode(0x9e), Instruction::Alu(AluOperation::SubtractWithBorrow, Register::M));
+        assert_eq!(decode(0xbf), Instruction::Alu(AluOperation::Compare, Register::A));
+        assert_eq!(decode(0xc6), Instruction::AluImmediate(AluOperation::Add));
+        assert_eq!(decode(0xfe), Instruction::AluImmediate(AluOperation::Compare));
+        assert_eq!(count_matching(|i| matches!(i, Instruction::Alu(_, _))), 64);
+        assert_eq!(count_matching(|i| matches!(i, Instruction::AluImmediate(_))), 8);
+    }
+
+    #[test]
+    fn test_decode_register_pairs() {
+        assert_eq!(decode(0x01), Instruction::Lxi(RegisterPair::BC));
+        assert_eq!(decode(0x31), Instruction::Lxi(RegisterPair::SP));
+        assert_eq!(decode(0x13), Instruction::Inx(RegisterPair::DE));
+        assert_eq!(decode(0x29), Instruction::Dad(RegisterPair::HL));
+        assert_eq!(decode(0x3b), Instruction::Dcx(RegisterPair::SP));
+        assert_eq!(decode(0x0a), Instruction::Ldax(RegisterPair::BC));
+        assert_eq!(decode(0x12), Instruction::Stax(RegisterPair::DE));
+        assert_eq!(decode(0xf5), Instruction::Push(RegisterPair::PSW));
+        assert_eq!(decode(0xe1), Instruction::Pop(RegisterPair::HL));
+    }
+
+    #[test]
+    fn test_decode_single_register() {
+        assert_eq!(decode(0x04), Instruction::Inr(Register::B));
+        assert_eq!(decode(0x35), Instruction::Dcr(Register::M));
+        assert_eq!(decode(0x3e), Instruction::Mvi(Register::A));
+    }
+
+    #[test]
+    fn test_decode_accumulator_group() {
+        assert_eq!(decode(0x07), Instruction::Rlc);
+        assert_eq!(decode(0x0f), Instruction::Rrc);
+        assert_eq!(decode(0x17), Instruction::Ral);
+        assert_eq!(decode(0x1f), Instruction::Rar);
+        assert_eq!(decode(0x27), Instruction::Daa);
+        assert_eq!(decode(0x2f), Instruction::Cma);
+        assert_eq!(decode(0x37), Instruction::Stc);
+        assert_eq!(decode(0x3f), Instruction::Cmc);
+    }
+
+    #[test]
+    fn test_decode_branches() {
+        assert_eq!(decode(0xc3), Instruction::Jmp);
+        assert_eq!(decode(0xc2), Instruction::Jcc(Condition::NotZero));
+        assert_eq!(decode(0xfa), Instruction::Jcc(Condition::Minus));
+        assert_eq!(decode(0xcd), Instruction::Call);
+        assert_eq!(decode(0xe4), Instruction::Ccc(Condition::ParityOdd));
+        assert_eq!(decode(0xc9), Instruction::Ret);
+        assert_eq!(decode(0xd8), Instruction::Rcc(Condition::Carry));
+        assert_eq!(decode(0xc7), Instruction::Rst(RestartVector::Rst0));
+        assert_eq!(decode(0xff), Instruction::Rst(RestartVector::Rst7));
+        assert_eq!(count_matching(|i| matches!(i, Instruction::Jcc(_))), 8);
+        assert_eq!(count_matching(|i| matches!(i, Instruction::Ccc(_))), 8);
+        assert_eq!(count_matching(|i| matches!(i, Instruction::Rcc(_))), 8);
+        assert_eq!(count_matching(|i| matches!(i, Instruction::Rst(_))), 8);
+    }
+
+    #[test]
+    fn test_restart_vectors() {
+        let op_codes = [0xc7, 0xcf, 0xd7, 0xdf, 0xe7, 0xef, 0xf7, 0xff];
+        for (number, vector) in RestartVector::ALL.iter().enumerate() {
+            assert_eq!(vector.number() as usize, number);
+            assert_eq!(vector.address(), number as u16 * 8);
+            assert_eq!(vector.op_code(), op_codes[number]);
+            assert_eq!(RestartVector::from_op_code(op_codes[number]), Some(*vector));
+            assert_eq!(RestartVector::from_number(number as u8), Some(*vector));
+        }
+        assert_eq!(RestartVector::from_number(8), None);
+        assert_eq!(RestartVector::from_op_code(0xcd), None);
+    }
+
+    #[test]
+    fn test_decode_undocumented() {
+        for &op_code in [0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38].iter() {
+            assert_eq!(decode(op_code), Instruction::Nop);
+        }
+        assert_eq!(decode(0xcb), Instruction::Jmp);
+        assert_eq!(decode(0xd9), Instruction::Ret);
+        for &op_code in [0xdd, 0xed, 0xfd].iter() {
+            assert_eq!(decode(op_code), Instruction::Call);
+        }
+        assert_eq!((0..=255_u8).filter(|op_code| is_undocumented(*op_code)).count(), 12);
+        assert!(!is_undocumented(0x00) && !is_undocumented(0xc3));
+    }
+
+    #[test]
+    fn test_decode_all_op_codes() {
+        let lengths: usize = (0..=255_u8).map(|op_code| decode(op_code).length() as usize).sum();
+        // 18 two-byte and 30 three-byte instructions, counting the undocumented JMP and CALLs
+        assert_eq!(lengths, 256 + 18 + 30 * 2);
+        assert_eq!(decode(0xcb).cycles(), 10);
+        assert_eq!(decode(0xd9).cycles(), 10);
+        assert_eq!(decode(0xfd).cycles(), 17);
+        assert_eq!(decode(0x38).cycles(), 4);
+    }
+
+    #[test]
+    fn test_length() {
+        assert_eq!(decode(0x00).length(), 1);
+        assert_eq!(decode(0x06).length(), 2);
+        assert_eq!(decode(0xdb).length(), 2);
+        assert_eq!(decode(0x21).length(), 3);
+        assert_eq!(decode(0xcd).length(), 3);
+        assert_eq!(decode(0xc9).length(), 1);
+    }
+
+    #[test]
+    fn test_cycles() {
+        assert_eq!(decode(0x41).cycles(), 5);
+        assert_eq!(decode(0x46).cycles(), 7);
+        assert_eq!(decode(0x36).cycles(), 10);
+        assert_eq!(decode(0x34).cycles(), 10);
+        assert_eq!(decode(0x86).cycles(), 7);
+        assert_eq!(decode(0x2a).cycles(), 16);
+        assert_eq!(decode(0xe3).cycles(), 18);
+        assert_eq!(decode(0xcd).cycles(), 17);
+        assert_eq!(decode(0xc4).cycles(), 11);
+        assert_eq!(decode(0xc4).cycles_when_taken(), 17);
+        assert_eq!(decode(0xc0).cycles(), 5);
+        assert_eq!(decode(0xc0).cycles_when_taken(), 11);
+        assert_eq!(decode(0xc2).cycles_when_taken(), 10);
+    }
+}
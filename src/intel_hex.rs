@@ -0,0 +1,200 @@
+use crate::loader::{address_of, append, decode_hex, set_start_address, FormatError, LoadedImage, Loader};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+// Data bytes per record written by `encode`
+const BYTES_PER_RECORD: usize = 16;
+
+/// Intel HEX, as emitted by most 8080 assemblers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IntelHex;
+
+impl Loader for IntelHex {
+    fn name(&self) -> &'static str {
+        "Intel HEX"
+    }
+
+    fn parse(&self, input: &str) -> Result<LoadedImage, FormatError> {
+        parse(input)
+    }
+
+    fn is_record(&self, text: &str) -> bool {
+        decode_record(text, 1).is_ok_and(|bytes| bytes[3] <= START_LINEAR_ADDRESS)
+    }
+}
+
+/// Parses Intel HEX text. Extended address records are accepted as long as everything
+/// still lands within 64 KiB, anything after the end of file record is ignored.
+pub fn parse(input: &str) -> Result<LoadedImage, FormatError> {
+    let mut image = LoadedImage::default();
+    let mut base: usize = 0;
+    for (index, text) in input.lines().enumerate() {
+        let line = index + 1;
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        let bytes = decode_record(text, line)?;
+        let address = address_of(&bytes[1..3]);
+        let record_type = bytes[3];
+        let data = &bytes[4..bytes.len() - 1];
+        match record_type {
+            DATA => append(&mut image, base + address, data, line)?,
+            END_OF_FILE => return Ok(image),
+            EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS => {
+                let value = address_of(sized(data, 2, line)?);
+                base = if record_type == EXTENDED_SEGMENT_ADDRESS { value << 4 } else { value << 16 };
+                if base > 0xFFFF {
+                    return Err(FormatError::AddressOutOfRange { line });
+                }
+            },
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {
+                let data = sized(data, 4, line)?;
+                let high = address_of(&data[..2]);
+                let low = address_of(&data[2..]);
+                let start = if record_type == START_SEGMENT_ADDRESS { (high << 4) + low } else { (high << 16) | low };
+                set_start_address(&mut image, start, line)?;
+            },
+            _ => return Err(FormatError::UnknownRecordType { line, record_type }),
+        }
+    }
+    Err(FormatError::MissingEndOfFile)
+}
+
+/// Intel HEX text placing `data` at `origin`, with a start address record if given.
+pub fn encode(origin: u16, data: &[u8], start_address: Option<u16>) -> String {
+    let mut output = String::new();
+    for (index, chunk) in data.chunks(BYTES_PER_RECORD).enumerate() {
+        let address = origin.wrapping_add((index * BYTES_PER_RECORD) as u16);
+        write_record(&mut output, address, DATA, chunk);
+    }
+    if let Some(start_address) = start_address {
+        write_record(&mut output, 0, START_LINEAR_ADDRESS, &[0, 0, (start_address >> 8) as u8, start_address as u8]);
+    }
+    write_record(&mut output, 0, END_OF_FILE, &[]);
+    output
+}
+
+fn write_record(output: &mut String, address: u16, record_type: u8, data: &[u8]) {
+    let mut record = vec![data.len() as u8, (address >> 8) as u8, address as u8, record_type];
+    record.extend_from_slice(data);
+    record.push(checksum_of(&record));
+    output.push(':');
+    for byte in record {
+        output.push_str(&format!("{:02X}", byte));
+    }
+    output.push('\n');
+}
+
+// Byte count, address, record type, data and checksum of a record, with the checksum verified
+fn decode_record(text: &str, line: usize) -> Result<Vec<u8>, FormatError> {
+    let digits = text.strip_prefix(':').ok_or(FormatError::MissingStartCode { line })?;
+    let bytes = decode_hex(digits, line)?;
+    if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+        return Err(FormatError::InvalidLength { line });
+    }
+    let (record, checksum) = bytes.split_at(bytes.len() - 1);
+    let expected = checksum_of(record);
+    if expected != checksum[0] {
+        return Err(FormatError::ChecksumMismatch { line, expected, actual: checksum[0] });
+    }
+    Ok(bytes)
+}
+
+fn sized(data: &[u8], size: usize, line: usize) -> Result<&[u8], FormatError> {
+    if data.len() != size {
+        return Err(FormatError::InvalidLength { line });
+    }
+    Ok(data)
+}
+
+// Two's complement of the sum of the record bytes
+fn checksum_of(record: &[u8]) -> u8 {
+    record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::Segment;
+
+    #[test]
+    fn test_parse_data_records() {
+        let image = parse(":03000000010203F7\n:020003000405F2\n:02100000AABB89\n:00000001FF\n").unwrap();
+        assert_eq!(image.segments, vec![
+            Segment { origin: 0x0000, data: vec![0x01, 0x02, 0x03, 0x04, 0x05] },
+            Segment { origin: 0x1000, data: vec![0xAA, 0xBB] },
+        ]);
+        assert_eq!(image.start_address, None);
+    }
+
+    #[test]
+    fn test_parse_start_and_extended_address_records() {
+        let input = "\
+:020000020100FB
+:0100000001FE
+:0400000300100020C9
+:00000001FF
+";
+        let image = parse(input).unwrap();
+        assert_eq!(image.segments, vec![Segment { origin: 0x1000, data: vec![0x01] }]);
+        assert_eq!(image.start_address, Some(0x0120));
+
+        let image = parse(":0400000500000100F6\r\n:00000001FF\r\n").unwrap();
+        assert_eq!(image.start_address, Some(0x0100));
+    }
+
+    #[test]
+    fn test_parse_ignores_trailing_input() {
+        let image = parse("\n:0100000001FE\n\n:00000001FF\ngarbage").unwrap();
+        assert_eq!(image.segments, vec![Segment { origin: 0x0000, data: vec![0x01] }]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse("0100000001FE"), Err(FormatError::MissingStartCode { line: 1 }));
+        assert_eq!(parse(":01000000G1FE"), Err(FormatError::InvalidDigit { line: 1 }));
+        assert_eq!(parse(":0200000001FE"), Err(FormatError::InvalidLength { line: 1 }));
+        assert_eq!(parse(":0100000001FE\n:00000001"), Err(FormatError::InvalidLength { line: 2 }));
+        assert_eq!(
+            parse(":0100000001FF"),
+            Err(FormatError::ChecksumMismatch { line: 1, expected: 0xFE, actual: 0xFF }),
+        );
+        assert_eq!(parse(":00000006FA"), Err(FormatError::UnknownRecordType { line: 1, record_type: 0x06 }));
+        assert_eq!(parse(":02FFFF000102FD"), Err(FormatError::AddressOutOfRange { line: 1 }));
+        assert_eq!(parse(":020000040001F9"), Err(FormatError::AddressOutOfRange { line: 1 }));
+        assert_eq!(parse(":0100000001FE\n"), Err(FormatError::MissingEndOfFile));
+    }
+
+    #[test]
+    fn test_encode() {
+        let data: Vec<u8> = (0..18).collect();
+        let expected = "\
+:10FFE000000102030405060708090A0B0C0D0E0F99
+:02FFF0001011EE
+:0400000500000100F6
+:00000001FF
+";
+        assert_eq!(encode(0xFFE0, &data, Some(0x0100)), expected);
+        assert_eq!(encode(0x0000, &[], None), ":00000001FF\n");
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let data: Vec<u8> = (0..=255).collect();
+        let image = parse(&encode(0x1234, &data, Some(0x1234))).unwrap();
+        assert_eq!(image.segments, vec![Segment { origin: 0x1234, data }]);
+        assert_eq!(image.start_address, Some(0x1234));
+    }
+
+    #[test]
+    fn test_error_display() {
+        let error = FormatError::ChecksumMismatch { line: 3, expected: 0xFE, actual: 0xFF };
+        assert_eq!(error.to_string(), "line 3: checksum 0xff does not match computed 0xfe");
+    }
+}
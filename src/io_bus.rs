@@ -0,0 +1,102 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::rc::Rc;
+
+/// The 256 I/O ports addressed by the IN and OUT instructions.
+pub trait IoBus: Debug {
+    /// Value an IN instruction reads from `port`.
+    fn read_port(&mut self, port: u8) -> u8;
+
+    /// Handles an OUT instruction writing `value` to `port`.
+    fn write_port(&mut self, port: u8, value: u8);
+}
+
+/// Bus with nothing attached: writes are discarded and reads see a floating data bus.
+#[derive(Debug, Default)]
+pub struct NullIoBus;
+
+impl IoBus for NullIoBus {
+    fn read_port(&mut self, _port: u8) -> u8 {
+        0xFF
+    }
+
+    fn write_port(&mut self, _port: u8, _value: u8) {}
+}
+
+#[derive(Debug, Default)]
+struct Recording {
+    inputs: HashMap<u8, u8>,
+    reads: Vec<u8>,
+    writes: Vec<(u8, u8)>,
+}
+
+/// Bus recording every port access. Clones share the same recording, so a test can keep
+/// a handle to the bus it hands over to the CPU.
+#[derive(Debug, Default, Clone)]
+pub struct RecordingIoBus {
+    recording: Rc<RefCell<Recording>>,
+}
+
+impl RecordingIoBus {
+    /// Bus with nothing recorded, reading 0xFF from every port.
+    pub fn new() -> RecordingIoBus {
+        RecordingIoBus::default()
+    }
+
+    /// Value read from `port` from now on.
+    pub fn set_input(&self, port: u8, value: u8) {
+        self.recording.borrow_mut().inputs.insert(port, value);
+    }
+
+    /// Ports read so far, in order.
+    pub fn reads(&self) -> Vec<u8> {
+        self.recording.borrow().reads.clone()
+    }
+
+    /// Ports written so far along with the values, in order.
+    pub fn writes(&self) -> Vec<(u8, u8)> {
+        self.recording.borrow().writes.clone()
+    }
+}
+
+impl IoBus for RecordingIoBus {
+    fn read_port(&mut self, port: u8) -> u8 {
+        let mut recording = self.recording.borrow_mut();
+        recording.reads.push(port);
+        *recording.inputs.get(&port).unwrap_or(&0)
+    }
+
+    fn write_port(&mut self, port: u8, value: u8) {
+        self.recording.borrow_mut().writes.push((port, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_null_bus_reads_floating() {
+        let mut bus = NullIoBus;
+        bus.write_port(0x10, 0x12);
+        assert_eq!(bus.read_port(0x10), 0xFF)
+    }
+
+    #[test]
+    fn test_recording_bus_reads() {
+        let mut bus = RecordingIoBus::new();
+        bus.set_input(0x01, 0x42);
+        assert_eq!(bus.read_port(0x01), 0x42);
+        assert_eq!(bus.read_port(0x02), 0);
+        assert_eq!(bus.reads(), vec![0x01, 0x02])
+    }
+
+    #[test]
+    fn test_recording_bus_shares_recording() {
+        let bus = RecordingIoBus::new();
+        let mut handle = bus.clone();
+        handle.write_port(0x03, 0x07);
+        assert_eq!(bus.writes(), vec![(0x03, 0x07)])
+    }
+}
@@ -0,0 +1,36 @@
+//! Intel 8080 CPU emulator.
+//!
+//! A [`Cpu`] executes code out of a [`Memory`] and talks to peripherals through an
+//! [`IoBus`], while [`MemoryMappedDevice`]s attached to [`Memory`] observe accesses to
+//! their address ranges. It can be driven an instruction at a time with [`Cpu::step`],
+//! for a number of T-states with [`Cpu::run_for_cycles`] or until it halts or runs out of
+//! the loaded program with [`Cpu::emulate`].
+//!
+//! Programs are loaded as raw binaries or in one of the object file formats implementing
+//! [`Loader`]: [`IntelHex`], [`SRecord`] and [`TektronixHex`].
+
+#![warn(missing_docs)]
+
+mod cpu;
+mod decoder;
+mod intel_hex;
+mod io_bus;
+mod loader;
+mod memory;
+mod memory_mapped_device;
+mod registers;
+mod srecord;
+mod tektronix_hex;
+
+pub use crate::cpu::{Cpu, CpuError, EmulateOutcome, ExecutedInstruction, HaltMode, StepOutcome};
+pub use crate::decoder::{
+    decode, is_undocumented, AluOperation, Condition, Instruction, Operand, Register, RegisterPair, RestartVector,
+};
+pub use crate::intel_hex::IntelHex;
+pub use crate::io_bus::{IoBus, NullIoBus, RecordingIoBus};
+pub use crate::loader::{detect, Format, FormatError, LoadedImage, Loader, Segment};
+pub use crate::memory::{BankSelector, LoadError, Memory, MemoryBuilder, MemoryError, RomWritePolicy, N_BYTES};
+pub use crate::memory_mapped_device::MemoryMappedDevice;
+pub use crate::registers::{Flags, Registers};
+pub use crate::srecord::SRecord;
+pub use crate::tektronix_hex::TektronixHex;
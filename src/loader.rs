@@ -0,0 +1,223 @@
+use crate::intel_hex::IntelHex;
+use crate::srecord::SRecord;
+use crate::tektronix_hex::TektronixHex;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Debug;
+
+/// Contiguous run of bytes to be placed at `origin`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// Address of the first byte.
+    pub origin: u16,
+    /// Bytes in address order.
+    pub data: Vec<u8>,
+}
+
+/// Contents of an object file: the data to load and where execution starts, if stated.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LoadedImage {
+    /// Data records merged into runs of consecutive addresses, in file order.
+    pub segments: Vec<Segment>,
+    /// Address given by the start or termination record, if any.
+    pub start_address: Option<u16>,
+}
+
+/// Malformed object file, with the 1-based line the problem was found on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatError {
+    /// Record not starting with the start code of the format, such as ':'.
+    #[allow(missing_docs)]
+    MissingStartCode { line: usize },
+    /// Character that is not a hex digit.
+    #[allow(missing_docs)]
+    InvalidDigit { line: usize },
+    /// Record shorter or longer than its byte count says.
+    #[allow(missing_docs)]
+    InvalidLength { line: usize },
+    /// Checksum of the record, `actual`, differing from the one computed, `expected`.
+    #[allow(missing_docs)]
+    ChecksumMismatch { line: usize, expected: u8, actual: u8 },
+    /// Record type the format does not define.
+    #[allow(missing_docs)]
+    UnknownRecordType { line: usize, record_type: u8 },
+    /// Data or start address beyond the 64 KiB address space.
+    #[allow(missing_docs)]
+    AddressOutOfRange { line: usize },
+    /// Input ending before the end of file or termination record.
+    MissingEndOfFile,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormatError::MissingStartCode { line } => write!(f, "line {}: record does not start with a start code", line),
+            FormatError::InvalidDigit { line } => write!(f, "line {}: invalid hex digit", line),
+            FormatError::InvalidLength { line } => write!(f, "line {}: record length does not match its byte count", line),
+            FormatError::ChecksumMismatch { line, expected, actual } =>
+                write!(f, "line {}: checksum {:#04x} does not match computed {:#04x}", line, actual, expected),
+            FormatError::UnknownRecordType { line, record_type } =>
+                write!(f, "line {}: unknown record type {:#04x}", line, record_type),
+            FormatError::AddressOutOfRange { line } => write!(f, "line {}: address beyond 0xffff", line),
+            FormatError::MissingEndOfFile => write!(f, "missing end of file record"),
+        }
+    }
+}
+
+impl Error for FormatError {}
+
+/// Text object file format produced by assemblers and monitors.
+pub trait Loader: Debug {
+    /// Name of the format, for messages.
+    fn name(&self) -> &'static str;
+
+    /// Contents of an object file in this format.
+    fn parse(&self, input: &str) -> Result<LoadedImage, FormatError>;
+
+    /// Whether `text` is a single well-formed record, checksum included.
+    fn is_record(&self, text: &str) -> bool;
+}
+
+/// Text format of `contents`, judging by its first non-blank line, which has to be a valid
+/// record of that format. `None` means the contents are to be taken as a raw binary.
+pub fn detect(contents: &[u8]) -> Option<&'static dyn Loader> {
+    let contents = std::str::from_utf8(contents).ok().filter(|contents| contents.is_ascii())?;
+    let first = contents.lines().map(str::trim).find(|line| !line.is_empty())?;
+    let loader: &'static dyn Loader = match first.as_bytes()[0] {
+        b':' => &IntelHex,
+        b'S' => &SRecord,
+        b'%' => &TektronixHex,
+        _ => return None,
+    };
+    Some(loader).filter(|loader| loader.is_record(first))
+}
+
+/// How to interpret the contents of a ROM.
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    /// Whatever `detect` makes of it.
+    Detect,
+    /// A binary loaded as it is.
+    Raw,
+    /// An object file in the given format.
+    Object(&'static dyn Loader),
+}
+
+impl Format {
+    /// Format named on the command line: auto, raw, ihex, srec or tekhex.
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "auto" => Some(Format::Detect),
+            "raw" => Some(Format::Raw),
+            "ihex" => Some(Format::Object(&IntelHex)),
+            "srec" => Some(Format::Object(&SRecord)),
+            "tekhex" => Some(Format::Object(&TektronixHex)),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Format {
+    fn eq(&self, other: &Format) -> bool {
+        match (self, other) {
+            (Format::Detect, Format::Detect) | (Format::Raw, Format::Raw) => true,
+            (Format::Object(loader), Format::Object(other)) => loader.name() == other.name(),
+            _ => false,
+        }
+    }
+}
+
+pub(crate) fn decode_hex(digits: &str, line: usize) -> Result<Vec<u8>, FormatError> {
+    if !digits.is_ascii() {
+        return Err(FormatError::InvalidDigit { line });
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(FormatError::InvalidLength { line });
+    }
+    (0..digits.len()).step_by(2)
+        .map(|index| u8::from_str_radix(&digits[index..index + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| FormatError::InvalidDigit { line })
+}
+
+// Address formed by the big-endian bytes of a record
+pub(crate) fn address_of(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |address, byte| (address << 8) | *byte as usize)
+}
+
+/// Adds `data` at `address`, extending the last segment when the data directly follows it.
+pub(crate) fn append(image: &mut LoadedImage, address: usize, data: &[u8], line: usize) -> Result<(), FormatError> {
+    if address > 0xFFFF || address + data.len() > 0x10000 {
+        return Err(FormatError::AddressOutOfRange { line });
+    }
+    if let Some(last) = image.segments.last_mut() {
+        if last.origin as usize + last.data.len() == address {
+            last.data.extend_from_slice(data);
+            return Ok(());
+        }
+    }
+    image.segments.push(Segment { origin: address as u16, data: data.to_vec() });
+    Ok(())
+}
+
+pub(crate) fn set_start_address(image: &mut LoadedImage, address: usize, line: usize) -> Result<(), FormatError> {
+    if address > 0xFFFF {
+        return Err(FormatError::AddressOutOfRange { line });
+    }
+    image.start_address = Some(address as u16);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(b":00000001FF\n").map(|loader| loader.name()), Some("Intel HEX"));
+        assert_eq!(detect(b"\r\nS9030000FC\n").map(|loader| loader.name()), Some("Motorola S-record"));
+        assert_eq!(detect(b"%0781010\n").map(|loader| loader.name()), Some("Tektronix extended hex"));
+        assert!(detect(&[0x3a, 0xc3, 0x00]).is_none());
+        assert!(detect(&[0xc3, 0x00, 0x01]).is_none());
+        assert!(detect(b"").is_none());
+    }
+
+    #[test]
+    fn test_detect_needs_a_valid_record() {
+        // LDA 0x4242, MOV D,E and DCR H followed by printable bytes, all plain ASCII
+        assert!(detect(b":BB\nv").is_none());
+        assert!(detect(b"SSSS").is_none());
+        assert!(detect(b"%%%%").is_none());
+        assert!(detect(b":00000001FE\n").is_none());
+        assert!(detect(b":0000000AF6\n").is_none());
+        assert!(detect(b"S4030000FC\n").is_none());
+        assert!(detect(b"\n\n  :00000001FF  \ngarbage").is_some());
+    }
+
+    #[test]
+    fn test_format_from_name() {
+        assert_eq!(Format::from_name("raw"), Some(Format::Raw));
+        assert_eq!(Format::from_name("auto"), Some(Format::Detect));
+        assert_eq!(Format::from_name("srec").map(|format| match format {
+            Format::Object(loader) => loader.name(),
+            _ => "",
+        }), Some("Motorola S-record"));
+        assert_ne!(Format::from_name("ihex"), Format::from_name("tekhex"));
+        assert_eq!(Format::from_name("bin"), None);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut image = LoadedImage::default();
+        append(&mut image, 0x0100, &[0x01], 1).unwrap();
+        append(&mut image, 0x0101, &[0x02], 2).unwrap();
+        append(&mut image, 0xFFFF, &[0x03], 3).unwrap();
+        assert_eq!(image.segments, vec![
+            Segment { origin: 0x0100, data: vec![0x01, 0x02] },
+            Segment { origin: 0xFFFF, data: vec![0x03] },
+        ]);
+        assert_eq!(append(&mut image, 0xFFFF, &[0x04, 0x05], 4), Err(FormatError::AddressOutOfRange { line: 4 }));
+        assert_eq!(append(&mut image, 0x10000, &[], 4), Err(FormatError::AddressOutOfRange { line: 4 }));
+        assert_eq!(set_start_address(&mut image, 0x10000, 5), Err(FormatError::AddressOutOfRange { line: 5 }));
+    }
+}
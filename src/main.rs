@@ -1,8 +1,439 @@
-mod cpu;
-mod memory;
-mod registers;
-mod op_code;
+use emu8080::{Cpu, CpuError, Format, LoadError, Memory, StepOutcome};
+use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::exit;
+
+const USAGE: &str = "usage: emu8080 [options] <rom>[@address][:format]...
+
+options:
+    --pc <address>             start address, defaults to the one stated by the first
+                               object file or else the machine's entry point
+    --sp <address>             initial stack pointer, defaults to the machine's stack
+    --machine <bare|cpm>       machine profile, defaults to bare
+    --max-instructions <n>     stop after executing n instructions
+    --max-cycles <n>           stop once n T-states have elapsed
+
+Addresses and counts are decimal or 0x-prefixed hexadecimal. ROMs are raw binaries or
+Intel HEX, Motorola S-record and Tektronix extended hex files, detected from their
+contents unless a format of raw, ihex, srec or tekhex is given. Raw binaries without an
+address are loaded at the machine's entry point.
+
+Both machines are 64 KiB of RAM holding the ROMs; the machine only sets the entry point,
+the stack and, for cpm, the BDOS console calls. The run stops on HLT, on a limit, or when
+execution reaches an address no ROM was loaded at.
+
+Exits with 1 on a CPU fault, 2 on invalid arguments, 3 if a ROM cannot be loaded, 4 if the
+program's output cannot be written and 5 if execution left the ROMs.";
+
+const EXIT_FAULT: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_LOAD: i32 = 3;
+const EXIT_OUTPUT: i32 = 4;
+const EXIT_LEFT_IMAGE: i32 = 5;
+
+// Entry point of the CP/M BDOS, called with the function number in C
+const BDOS_ADDRESS: u16 = 0x0005;
+const CPM_TPA_ADDRESS: u16 = 0x0100;
+const CPM_STACK_ADDRESS: u16 = 0xf000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Machine {
+    /// Plain CPU and RAM, execution starts at 0.
+    Bare,
+    /// Programs run from 0x0100, console BDOS calls are serviced by the host and a jump
+    /// to 0 (warm boot) ends the run.
+    Cpm,
+}
+
+impl Machine {
+    fn entry_point(self) -> u16 {
+        match self {
+            Machine::Bare => 0,
+            Machine::Cpm => CPM_TPA_ADDRESS,
+        }
+    }
+
+    fn stack_pointer(self) -> u16 {
+        match self {
+            Machine::Bare => 0,
+            Machine::Cpm => CPM_STACK_ADDRESS,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    images: Vec<(PathBuf, Option<u16>, Format)>,
+    program_counter: Option<u16>,
+    stack_pointer: Option<u16>,
+    machine: Machine,
+    max_instructions: Option<u64>,
+    max_cycles: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+enum StopReason {
+    Halted,
+    WarmBoot,
+    InstructionLimit,
+    CycleLimit,
+    /// Whatever reads the program's output went away.
+    OutputClosed,
+    /// Execution reached an address no ROM was loaded at.
+    LeftImage { program_counter: u16 },
+}
+
+#[derive(Debug)]
+enum RunError {
+    Fault(CpuError),
+    Output(io::Error),
+}
+
+impl From<CpuError> for RunError {
+    fn from(error: CpuError) -> RunError {
+        RunError::Fault(error)
+    }
+}
 
 fn main() {
-    println!("Hello, world!");
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            exit(EXIT_USAGE);
+        }
+    };
+    let (memory, start_address) = match load_images(&options) {
+        Ok(loaded) => loaded,
+        Err(message) => {
+            eprintln!("{}", message);
+            exit(EXIT_LOAD);
+        }
+    };
+    let mut cpu = Cpu::new(memory);
+    let entry_point = start_address.unwrap_or_else(|| options.machine.entry_point());
+    cpu.set_program_counter(options.program_counter.unwrap_or(entry_point));
+    cpu.set_stack_pointer(options.stack_pointer.unwrap_or_else(|| options.machine.stack_pointer()));
+
+    let mut instructions = 0;
+    let result = run(&mut cpu, &options, &mut io::stdout(), &mut instructions);
+    let flushed = io::stdout().flush().map_err(RunError::Output);
+    match result.and_then(|reason| flushed.map(|()| reason)) {
+        Ok(reason) => {
+            eprintln!("stopped: {:?}", reason);
+            eprintln!("{}", dump(&cpu, instructions));
+            if let StopReason::LeftImage { .. } = reason {
+                exit(EXIT_LEFT_IMAGE);
+            }
+        },
+        Err(RunError::Output(ref error)) if error.kind() == io::ErrorKind::BrokenPipe => {},
+        Err(RunError::Output(error)) => {
+            eprintln!("cannot write output: {}", error);
+            exit(EXIT_OUTPUT);
+        },
+        Err(RunError::Fault(error)) => {
+            eprintln!("fault: {}", error);
+            eprintln!("{}", dump(&cpu, instructions));
+            exit(EXIT_FAULT);
+        }
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options {
+        images: Vec::new(),
+        program_counter: None,
+        stack_pointer: None,
+        machine: Machine::Bare,
+        max_instructions: None,
+        max_cycles: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("missing value for {}", name));
+        match arg.as_str() {
+            "--pc" => options.program_counter = Some(parse_address(&value("--pc")?)?),
+            "--sp" => options.stack_pointer = Some(parse_address(&value("--sp")?)?),
+            "--machine" => {
+                options.machine = match value("--machine")?.as_str() {
+                    "bare" => Machine::Bare,
+                    "cpm" => Machine::Cpm,
+                    other => return Err(format!("unknown machine {}", other)),
+                }
+            },
+            "--max-instructions" => options.max_instructions = Some(parse_number(&value("--max-instructions")?)?),
+            "--max-cycles" => options.max_cycles = Some(parse_number(&value("--max-cycles")?)?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => {
+                let (rom, format) = arg.rsplit_once(':')
+                    .and_then(|(rom, name)| Some((rom, Format::from_name(name)?)))
+                    .unwrap_or((arg.as_str(), Format::Detect));
+                let image = match rom.rfind('@') {
+                    Some(index) => (PathBuf::from(&rom[..index]), Some(parse_address(&rom[index + 1..])?), format),
+                    None => (PathBuf::from(rom), None, format),
+                };
+                options.images.push(image);
+            }
+        }
+    }
+    if options.images.is_empty() {
+        return Err("no ROM given".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_number(value: &str) -> Result<u64, String> {
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number {}", value))
+}
+
+fn parse_address(value: &str) -> Result<u16, String> {
+    let number = parse_number(value)?;
+    if number > 0xffff {
+        return Err(format!("address {} out of range", value));
+    }
+    Ok(number as u16)
+}
+
+// Memory holding all images along with the first start address stated by any of them
+fn load_images(options: &Options) -> Result<(Memory, Option<u16>), String> {
+    let mut memory = Memory::new();
+    let mut start_address = None;
+    for (path, origin, format) in &options.images {
+        let origin = origin.unwrap_or_else(|| options.machine.entry_point());
+        let loaded = memory.load_file_as(path, origin, *format).map_err(|error| match error {
+            // These already name the file
+            LoadError::NotFound { .. } | LoadError::Io { .. } => error.to_string(),
+            _ => format!("cannot load {}: {}", path.display(), error),
+        })?;
+        start_address = start_address.or(loaded);
+    }
+    Ok((memory, start_address))
+}
+
+fn run(cpu: &mut Cpu, options: &Options, output: &mut dyn Write, instructions: &mut u64) -> Result<StopReason, RunError> {
+    loop {
+        if options.max_instructions.is_some_and(|max| *instructions >= max) {
+            return Ok(StopReason::InstructionLimit);
+        }
+        if options.max_cycles.is_some_and(|max| cpu.cycles() >= max) {
+            return Ok(StopReason::CycleLimit);
+        }
+        if options.machine == Machine::Cpm {
+            match cpu.program_counter() {
+                0 => return Ok(StopReason::WarmBoot),
+                BDOS_ADDRESS => {
+                    match call_bdos(cpu, output) {
+                        Err(error) if error.kind() == io::ErrorKind::BrokenPipe =>
+                            return Ok(StopReason::OutputClosed),
+                        result => result.map_err(RunError::Output)?,
+                    }
+                    continue;
+                },
+                _ => {},
+            }
+        }
+        let program_counter = cpu.program_counter();
+        if !cpu.is_halted() && !cpu.memory().is_in_image(program_counter) {
+            return Ok(StopReason::LeftImage { program_counter });
+        }
+        match cpu.step()? {
+            StepOutcome::Executed(_) => *instructions += 1,
+            StepOutcome::Halted => return Ok(StopReason::Halted),
+        }
+    }
+}
+
+// Services the console functions of the BDOS, writing to `output`, and returns to the caller
+fn call_bdos(cpu: &mut Cpu, output: &mut dyn Write) -> io::Result<()> {
+    let registers = *cpu.registers();
+    match registers.c {
+        2 => output.write_all(&[registers.e])?,
+        9 => {
+            let start = registers.get_de();
+            for offset in 0..=u16::MAX {
+                let character = cpu.memory().fetch_byte_at_offset(start.wrapping_add(offset));
+                if character == b'$' {
+                    break;
+                }
+                output.write_all(&[character])?;
+            }
+        },
+        _ => {},
+    }
+    let stack_pointer = cpu.stack_pointer();
+    let low = cpu.memory().fetch_byte_at_offset(stack_pointer) as u16;
+    let high = cpu.memory().fetch_byte_at_offset(stack_pointer.wrapping_add(1)) as u16;
+    cpu.set_stack_pointer(stack_pointer.wrapping_add(2));
+    cpu.set_program_counter(high << 8 | low);
+    Ok(())
+}
+
+fn dump(cpu: &Cpu, instructions: u64) -> String {
+    let registers = cpu.registers();
+    let flags = cpu.flags();
+    format!(
+        "PC={:04x} SP={:04x} A={:02x} B={:02x} C={:02x} D={:02x} E={:02x} H={:02x} L={:02x}\n\
+         S={} Z={} AC={} P={} CY={}\n\
+         instructions={} cycles={}",
+        cpu.program_counter(), cpu.stack_pointer(), registers.acc, registers.b, registers.c,
+        registers.d, registers.e, registers.h, registers.l,
+        flags.sign as u8, flags.zero as u8, flags.aux_carry as u8, flags.parity as u8, flags.carry as u8,
+        instructions, cpu.cycles(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emu8080::RomWritePolicy;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn create_options(machine: Machine) -> Options {
+        Options {
+            images: Vec::new(),
+            program_counter: None,
+            stack_pointer: None,
+            machine,
+            max_instructions: None,
+            max_cycles: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse(&[
+            "--machine", "cpm", "--pc", "0x0100", "--sp", "61440", "--max-cycles", "1000",
+            "boot.bin@0xf800", "prog.com", "prog.hex:raw", "data.bin@0x4000:ihex", "c:dir",
+        ]).unwrap();
+        assert_eq!(options.machine, Machine::Cpm);
+        assert_eq!(options.program_counter, Some(0x0100));
+        assert_eq!(options.stack_pointer, Some(0xf000));
+        assert_eq!(options.max_cycles, Some(1000));
+        assert_eq!(options.max_instructions, None);
+        assert_eq!(options.images, vec![
+            (PathBuf::from("boot.bin"), Some(0xf800), Format::Detect),
+            (PathBuf::from("prog.com"), None, Format::Detect),
+            (PathBuf::from("prog.hex"), None, Format::Raw),
+            (PathBuf::from("data.bin"), Some(0x4000), Format::from_name("ihex").unwrap()),
+            (PathBuf::from("c:dir"), None, Format::Detect),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--pc"]).is_err());
+        assert!(parse(&["--pc", "0x10000", "rom.bin"]).is_err());
+        assert!(parse(&["--machine", "zx81", "rom.bin"]).is_err());
+        assert!(parse(&["--verbose", "rom.bin"]).is_err());
+        assert!(parse(&["rom.bin@somewhere"]).is_err());
+        assert!(parse(&["rom.bin@somewhere:raw"]).is_err());
+    }
+
+    #[test]
+    fn test_load_images_with_format() {
+        let rom = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/rom.hex"));
+        let mut options = create_options(Machine::Bare);
+        options.images = vec![(rom.clone(), Some(0x2000), Format::Raw)];
+        let (memory, start_address) = load_images(&options).unwrap();
+        assert_eq!(start_address, None);
+        assert_eq!(memory.fetch_byte_at_offset(0x2000), b':');
+
+        options.images = vec![(rom, Some(0x2000), Format::Detect)];
+        let (memory, start_address) = load_images(&options).unwrap();
+        assert_eq!(start_address, Some(0x0100));
+        assert_eq!(memory.fetch_byte_at_offset(0x0100), 0x3e);
+    }
+
+    #[test]
+    fn test_run_stops_on_instruction_limit() {
+        let mut cpu = Cpu::new(Memory::with_image(0, &[0x00; 16]).unwrap());
+        let mut options = create_options(Machine::Bare);
+        options.max_instructions = Some(3);
+        let mut instructions = 0;
+        assert_eq!(run(&mut cpu, &options, &mut io::sink(), &mut instructions).unwrap(), StopReason::InstructionLimit);
+        assert_eq!(instructions, 3);
+        assert_eq!(cpu.program_counter(), 3);
+    }
+
+    #[test]
+    fn test_run_stops_outside_images() {
+        // MVI A,1 and then nothing but zeroed RAM
+        let mut cpu = Cpu::new(Memory::with_image(0, &[0x3e, 0x01]).unwrap());
+        let mut instructions = 0;
+        let reason = run(&mut cpu, &create_options(Machine::Bare), &mut io::sink(), &mut instructions).unwrap();
+        assert_eq!(reason, StopReason::LeftImage { program_counter: 2 });
+        assert_eq!(instructions, 1);
+    }
+
+    #[test]
+    fn test_run_halts_at_end_of_image() {
+        let mut cpu = Cpu::new(Memory::with_image(0, &[0x00, 0x76]).unwrap());
+        let mut instructions = 0;
+        let reason = run(&mut cpu, &create_options(Machine::Bare), &mut io::sink(), &mut instructions).unwrap();
+        assert_eq!(reason, StopReason::Halted);
+        assert_eq!(instructions, 2);
+    }
+
+    #[test]
+    fn test_run_reports_fault() {
+        let memory = Memory::builder()
+            .read_only(0x0000..=0x00ff)
+            .rom_write_policy(RomWritePolicy::Error)
+            .build();
+        let mut cpu = Cpu::new(memory);
+        cpu.memory_mut().load_image(0, &[0x00, 0x32, 0x00, 0x00]).unwrap();
+        let mut instructions = 0;
+        let result = run(&mut cpu, &create_options(Machine::Bare), &mut io::sink(), &mut instructions);
+        let error = CpuError::RomWrite { program_counter: 1, op_code: 0x32, address: 0x0000 };
+        assert!(matches!(result, Err(RunError::Fault(fault)) if fault == error));
+    }
+
+    #[test]
+    fn test_run_cpm_bdos_call_and_warm_boot() {
+        // MVI C,2; MVI E,'A'; CALL 5; JMP 0
+        let program = [0x0e, 0x02, 0x1e, 0x41, 0xcd, 0x05, 0x00, 0xc3, 0x00, 0x00];
+        let mut cpu = Cpu::new(Memory::with_image(0x100, &program).unwrap());
+        cpu.set_program_counter(0x100);
+        cpu.set_stack_pointer(0x200);
+        let mut output = Vec::new();
+        let mut instructions = 0;
+        let reason = run(&mut cpu, &create_options(Machine::Cpm), &mut output, &mut instructions).unwrap();
+        assert_eq!(reason, StopReason::WarmBoot);
+        assert_eq!(instructions, 4);
+        assert_eq!(cpu.stack_pointer(), 0x200);
+        assert_eq!(output, b"A");
+    }
+
+    #[derive(Debug)]
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_run_stops_when_output_closed() {
+        // MVI C,2; CALL 5
+        let program = [0x0e, 0x02, 0xcd, 0x05, 0x00];
+        let mut cpu = Cpu::new(Memory::with_image(0x100, &program).unwrap());
+        cpu.set_program_counter(0x100);
+        cpu.set_stack_pointer(0x200);
+        let mut instructions = 0;
+        let reason = run(&mut cpu, &create_options(Machine::Cpm), &mut ClosedPipe, &mut instructions).unwrap();
+        assert_eq!(reason, StopReason::OutputClosed);
+    }
 }
@@ -1,79 +1,1025 @@
-use std::path::Path;
+use crate::intel_hex;
+use crate::intel_hex::IntelHex;
+use crate::loader;
+use crate::loader::{Format, FormatError, LoadedImage, Loader};
+use crate::memory_mapped_device::MemoryMappedDevice;
+use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
 use std::fs::File;
+use std::io;
 use std::io::Read;
-use dirs::home_dir;
+use std::error::Error;
+use std::fmt;
 use std::fmt::Debug;
+use std::ops::{Range, RangeInclusive};
+use std::rc::Rc;
 
+/// Size of the address space.
 pub const N_BYTES: usize = 65536;
 
+// Granularity at which addresses are flagged as having a device attached
+const PAGE_SIZE: usize = 256;
+
+// Value read from addresses nothing drives the data bus for
+const OPEN_BUS_VALUE: u8 = 0xFF;
+
+/// What happens when the CPU writes to a read-only region.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RomWritePolicy {
+    /// Drop the write, as the hardware does.
+    Ignore,
+    /// Drop the write and record it, see `Memory::ignored_rom_writes`.
+    Record,
+    /// Fail the write with `MemoryError::RomWrite`.
+    Error,
+}
+
+/// Access rejected by the memory map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryError {
+    /// Write of `value` to a read-only `address` with `RomWritePolicy::Error` in effect.
+    #[allow(missing_docs)]
+    RomWrite { address: u16, value: u8 },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryError::RomWrite { address, value } =>
+                write!(f, "write of {:#04x} to ROM at {:#06x}", value, address),
+        }
+    }
+}
+
+impl Error for MemoryError {}
+
+/// Image that could not be loaded. Nothing is written to memory when loading fails.
+#[derive(Debug)]
+pub enum LoadError {
+    /// File at `path` that does not exist.
+    #[allow(missing_docs)]
+    NotFound { path: PathBuf },
+    /// File at `path` that could not be read.
+    #[allow(missing_docs)]
+    Io { path: PathBuf, error: io::Error },
+    /// Image running past 0xFFFF.
+    #[allow(missing_docs)]
+    Oversize { origin: u16, size: usize },
+    /// Image covering addresses an earlier image was loaded at.
+    #[allow(missing_docs)]
+    Overlap { image: Range<usize>, loaded: Range<usize> },
+    /// Image for a bank beyond `BankSelector::bank_count`.
+    #[allow(missing_docs)]
+    NoSuchBank { bank: usize },
+    /// Malformed object file.
+    Format(FormatError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotFound { path } => write!(f, "{}: no such file", path.display()),
+            LoadError::Io { path, error } => write!(f, "cannot read {}: {}", path.display(), error),
+            LoadError::Oversize { origin, size } =>
+                write!(f, "image of {} bytes does not fit at {:#06x}", size, origin),
+            LoadError::Overlap { image, loaded } => write!(
+                f,
+                "image at {:#06x}-{:#06x} overlaps one loaded at {:#06x}-{:#06x}",
+                image.start, image.end - 1, loaded.start, loaded.end - 1,
+            ),
+            LoadError::NoSuchBank { bank } => write!(f, "no bank {}", bank),
+            LoadError::Format(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io { error, .. } => Some(error),
+            LoadError::Format(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<FormatError> for LoadError {
+    fn from(error: FormatError) -> LoadError {
+        LoadError::Format(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    ReadWrite,
+    ReadOnly,
+    OpenBus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RegionKind {
+    Plain(Access),
+    // Accesses land at `source` plus the offset into the region modulo `size`
+    Mirror { source: u16, size: usize },
+}
+
+#[derive(Debug, Clone)]
+struct Region {
+    range: RangeInclusive<u16>,
+    kind: RegionKind,
+}
+
+#[derive(Debug)]
+struct AttachedDevice {
+    range: RangeInclusive<u16>,
+    // Reads go through `&Memory` but may still change the state of the device
+    device: RefCell<Box<dyn MemoryMappedDevice>>,
+}
+
+/// Handle for switching banks of a `Memory`, shared with the memory it came from so that
+/// it can be kept by the I/O port handler driving the bank select latch.
+#[derive(Debug, Clone)]
+pub struct BankSelector {
+    selected: Rc<Vec<Cell<usize>>>,
+    bank_count: usize,
+    window_size: usize,
+}
+
+impl BankSelector {
+    fn new(bank_count: usize, window_size: usize) -> BankSelector {
+        BankSelector {
+            selected: Rc::new(vec![Cell::new(0); N_BYTES / window_size]),
+            bank_count,
+            window_size,
+        }
+    }
+
+    /// Maps `bank` into the window covering addresses `window * window_size()` onwards.
+    /// Windows and banks beyond the configured counts wrap around, as with unconnected
+    /// select lines, so whatever value a program latches never fails.
+    pub fn select(&self, window: usize, bank: usize) {
+        self.selected[window % self.selected.len()].set(bank % self.bank_count);
+    }
+
+    /// Bank mapped into `window`, which wraps around like in `select`.
+    pub fn selected(&self, window: usize) -> usize {
+        self.selected[window % self.selected.len()].get()
+    }
+
+    /// Number of 64 KiB banks backing the address space.
+    pub fn bank_count(&self) -> usize {
+        self.bank_count
+    }
+
+    /// Size in bytes of the windows switched independently.
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    /// Number of windows, the address space divided by the window size.
+    pub fn window_count(&self) -> usize {
+        self.selected.len()
+    }
+
+    // Index into the storage of all banks
+    fn physical(&self, address: u16) -> usize {
+        if self.bank_count == 1 {
+            return address as usize;
+        }
+        self.selected[address as usize / self.window_size].get() * N_BYTES + address as usize
+    }
+}
+
+/// Declares the memory map of a machine, see `Memory::builder`. Regions declared later
+/// take precedence over earlier ones they overlap, addresses outside of any region are
+/// read-write.
+#[derive(Debug)]
+pub struct MemoryBuilder {
+    regions: Vec<Region>,
+    rom_write_policy: RomWritePolicy,
+    bank_count: usize,
+    window_size: usize,
+}
+
+impl MemoryBuilder {
+    /// RAM, the default for addresses outside of any region.
+    pub fn read_write(self, range: RangeInclusive<u16>) -> MemoryBuilder {
+        self.region(range, RegionKind::Plain(Access::ReadWrite))
+    }
+
+    /// ROM, writes to which are handled according to the `RomWritePolicy`.
+    pub fn read_only(self, range: RangeInclusive<u16>) -> MemoryBuilder {
+        self.region(range, RegionKind::Plain(Access::ReadOnly))
+    }
+
+    /// Unconnected addresses, reads return 0xFF and writes are dropped.
+    pub fn open_bus(self, range: RangeInclusive<u16>) -> MemoryBuilder {
+        self.region(range, RegionKind::Plain(Access::OpenBus))
+    }
+
+    /// Makes `range` repeat the contents of `source`, with the access rules of `source`.
+    /// Mirrors are not followed transitively.
+    pub fn mirror(self, range: RangeInclusive<u16>, source: RangeInclusive<u16>) -> MemoryBuilder {
+        assert!(!source.is_empty(), "mirror source must not be empty");
+        let size = *source.end() as usize - *source.start() as usize + 1;
+        self.region(range, RegionKind::Mirror { source: *source.start(), size })
+    }
+
+    /// How writes to read-only regions are handled, `RomWritePolicy::Ignore` by default.
+    pub fn rom_write_policy(mut self, rom_write_policy: RomWritePolicy) -> MemoryBuilder {
+        self.rom_write_policy = rom_write_policy;
+        self
+    }
+
+    /// Backs the address space with `bank_count` banks of 64 KiB, switched independently
+    /// in windows of `window_size` bytes through `Memory::bank_selector`. Every window
+    /// starts out on bank 0, the memory map applies to whichever bank is selected.
+    pub fn banks(mut self, bank_count: usize, window_size: usize) -> MemoryBuilder {
+        assert!(bank_count > 0, "at least one bank is needed");
+        assert!(window_size.is_power_of_two() && window_size <= N_BYTES, "window size must divide 64 KiB");
+        self.bank_count = bank_count;
+        self.window_size = window_size;
+        self
+    }
+
+    /// Zeroed memory with the declared map and no images.
+    pub fn build(self) -> Memory {
+        Memory {
+            mapping: vec![0; N_BYTES * self.bank_count],
+            regions: self.regions,
+            rom_write_policy: self.rom_write_policy,
+            bank_selector: BankSelector::new(self.bank_count, self.window_size),
+            ..Memory::new()
+        }
+    }
+
+    fn region(mut self, range: RangeInclusive<u16>, kind: RegionKind) -> MemoryBuilder {
+        self.regions.push(Region { range, kind });
+        self
+    }
+}
+
+/// The 64 KiB address space of the CPU.
 #[derive(Debug)]
 pub struct Memory {
     mapping: Vec<u8>,
+    images: Vec<Range<usize>>,
+    // Every image split at the bank windows, each piece along with the bank it was loaded into
+    bank_images: Vec<(usize, Range<usize>)>,
+    regions: Vec<Region>,
+    rom_write_policy: RomWritePolicy,
+    devices: Vec<AttachedDevice>,
+    device_pages: Vec<bool>,
+    bank_selector: BankSelector,
+    ignored_rom_writes: u64,
+    last_ignored_rom_write: Option<MemoryError>,
 }
 
 impl Memory {
-    pub fn load_rom(path: &Path) -> Memory {
-        let loaded_rom: Vec<u8> = read_file(path);
-        return Memory::new(loaded_rom)
+    /// Builder for memory with ROM, RAM, mirrored and unmapped regions.
+    pub fn builder() -> MemoryBuilder {
+        MemoryBuilder {
+            regions: Vec::new(),
+            rom_write_policy: RomWritePolicy::Ignore,
+            bank_count: 1,
+            window_size: N_BYTES,
+        }
+    }
+
+    /// Memory holding the contents of the given binary file at `origin`.
+    pub fn load_rom(path: &Path, origin: u16) -> Result<Memory, LoadError> {
+        let loaded_rom: Vec<u8> = read_file(path)?;
+        Memory::with_image(origin, &loaded_rom)
+    }
+
+    /// Memory holding `image` at `origin`.
+    pub fn with_image(origin: u16, image: &[u8]) -> Result<Memory, LoadError> {
+        let mut memory = Memory::new();
+        memory.load_image(origin, image)?;
+        Ok(memory)
+    }
+
+    /// Copies `image` into memory at `origin`, regardless of the memory map, into the
+    /// currently selected banks. Fails if the image runs past 0xFFFF or overlaps an image
+    /// loaded into the same banks before. The image counts for `is_in_image` while those
+    /// banks are selected.
+    pub fn load_image(&mut self, origin: u16, image: &[u8]) -> Result<(), LoadError> {
+        let pieces = self.place_image(origin, image.len(), None, &self.bank_images)?;
+        self.write_image(origin, image, pieces);
+        Ok(())
+    }
+
+    /// Loads every segment of `image` and returns its start address, if it has one. Nothing
+    /// is loaded unless all segments fit.
+    pub fn load_segments(&mut self, image: &LoadedImage) -> Result<Option<u16>, LoadError> {
+        let mut loaded = self.bank_images.clone();
+        let mut placed = Vec::new();
+        for segment in &image.segments {
+            let pieces = self.place_image(segment.origin, segment.data.len(), None, &loaded)?;
+            loaded.extend(pieces.iter().cloned());
+            placed.push(pieces);
+        }
+        for (pieces, segment) in placed.into_iter().zip(&image.segments) {
+            self.write_image(segment.origin, &segment.data, pieces);
+        }
+        Ok(image.start_address)
+    }
+
+    /// Loads the records of an object file at the addresses they state and returns its start
+    /// address, if any. Nothing is loaded unless the whole input is valid.
+    pub fn load_with(&mut self, loader: &dyn Loader, input: &str) -> Result<Option<u16>, LoadError> {
+        let image = loader.parse(input)?;
+        self.load_segments(&image)
+    }
+
+    /// `load_with` for Intel HEX.
+    pub fn load_intel_hex(&mut self, input: &str) -> Result<Option<u16>, LoadError> {
+        self.load_with(&IntelHex, input)
+    }
+
+    /// Loads `contents` in whichever object file format `loader::detect` recognises, or as a
+    /// raw binary at `origin` otherwise.
+    pub fn load_detected(&mut self, contents: &[u8], origin: u16) -> Result<Option<u16>, LoadError> {
+        self.load_as(Format::Detect, contents, origin)
+    }
+
+    /// Loads `contents` in the given format, raw binaries at `origin`, and returns the start
+    /// address of object files, if any.
+    pub fn load_as(&mut self, format: Format, contents: &[u8], origin: u16) -> Result<Option<u16>, LoadError> {
+        let format = match format {
+            Format::Detect => loader::detect(contents).map_or(Format::Raw, Format::Object),
+            format => format,
+        };
+        match format {
+            Format::Object(loader) => {
+                let input = std::str::from_utf8(contents).map_err(|error| {
+                    let line = contents[..error.valid_up_to()].iter().filter(|byte| **byte == b'\n').count() + 1;
+                    FormatError::InvalidDigit { line }
+                })?;
+                self.load_with(loader, input)
+            },
+            _ => {
+                self.load_image(origin, contents)?;
+                Ok(None)
+            },
+        }
+    }
+
+    /// Like `load_detected`, reading the contents from `path`.
+    pub fn load_file(&mut self, path: &Path, origin: u16) -> Result<Option<u16>, LoadError> {
+        self.load_file_as(path, origin, Format::Detect)
+    }
+
+    /// Like `load_as`, reading the contents from `path`.
+    pub fn load_file_as(&mut self, path: &Path, origin: u16, format: Format) -> Result<Option<u16>, LoadError> {
+        let contents = read_file(path)?;
+        self.load_as(format, &contents, origin)
+    }
+
+    /// Raw contents of `range` as Intel HEX, loadable again with `load_intel_hex`.
+    pub fn export_intel_hex(&self, range: RangeInclusive<u16>, start_address: Option<u16>) -> String {
+        intel_hex::encode(*range.start(), &self.export_binary(range), start_address)
+    }
+
+    /// Raw contents of `range`, loadable again with `load_image` at the start of the range.
+    pub fn export_binary(&self, range: RangeInclusive<u16>) -> Vec<u8> {
+        if range.is_empty() {
+            return Vec::new();
+        }
+        let size = *range.end() as usize - *range.start() as usize + 1;
+        self.fetch_bytes_at_offset(*range.start(), size)
+    }
+
+    /// Copies `image` into `bank` at `origin`, whatever bank is currently selected there.
+    /// Fails if the bank does not exist, the image runs past 0xFFFF or overlaps an image
+    /// loaded into the same bank before. The image counts for `is_in_image` while its bank
+    /// is selected.
+    pub fn load_image_into_bank(&mut self, bank: usize, origin: u16, image: &[u8]) -> Result<(), LoadError> {
+        if bank >= self.bank_selector.bank_count() {
+            return Err(LoadError::NoSuchBank { bank });
+        }
+        let pieces = self.place_image(origin, image.len(), Some(bank), &self.bank_images)?;
+        let start = bank * N_BYTES + origin as usize;
+        self.mapping[start..start + image.len()].copy_from_slice(image);
+        self.bank_images.extend(pieces);
+        Ok(())
+    }
+
+    /// Handle for switching banks, see `MemoryBuilder::banks`.
+    pub fn bank_selector(&self) -> BankSelector {
+        self.bank_selector.clone()
     }
 
-    pub fn instructions_len(&self) -> usize {
-        self.mapping.len()
+    /// Address ranges covered by the images loaded with `load_image` and the other loaders
+    /// writing through the selected banks, in load order.
+    pub fn images(&self) -> &[Range<usize>] {
+        &self.images
     }
 
+    /// Whether `address` holds a byte of a loaded image in the banks currently selected.
+    pub fn is_in_image(&self, address: u16) -> bool {
+        let bank = self.bank_selector.selected(address as usize / self.bank_selector.window_size());
+        self.bank_images.iter()
+            .any(|(loaded_bank, image)| *loaded_bank == bank && image.contains(&(address as usize)))
+    }
+
+    /// Byte the CPU reads at `pointer`, from an attached device, the selected bank or the
+    /// open bus according to the memory map.
     pub fn fetch_byte_at_offset(&self, pointer: u16) -> u8 {
-        return self.mapping[pointer as usize]
+        if let Some(attached) = self.find_device(pointer) {
+            return attached.device.borrow_mut().read(pointer - attached.range.start());
+        }
+        match self.resolve(pointer) {
+            (_, Access::OpenBus) => OPEN_BUS_VALUE,
+            (address, _) => self.mapping[self.bank_selector.physical(address)],
+        }
     }
 
-    pub fn fetch_bytes_at_offset(&self, pointer: u16, size: usize) -> &[u8] {
-        return &self.mapping[(pointer as usize)..(pointer as usize) + size]
+    /// Raw contents of the currently selected banks, regardless of the memory map. Panics
+    /// if the range runs past 0xFFFF.
+    pub fn fetch_bytes_at_offset(&self, pointer: u16, size: usize) -> Vec<u8> {
+        assert!(pointer as usize + size <= N_BYTES, "range of {} bytes does not fit at {:#06x}", size, pointer);
+        (pointer as usize..pointer as usize + size)
+            .map(|address| self.mapping[self.bank_selector.physical(address as u16)])
+            .collect()
     }
 
-    pub fn set_byte_at_offset(&mut self, pointer: u16, value: u8) {
-        self.mapping[(pointer as usize)] = value;
+    /// Byte the CPU writes at `pointer`, to an attached device or the selected bank. Writes to
+    /// read-only regions are handled according to the `RomWritePolicy`.
+    pub fn set_byte_at_offset(&mut self, pointer: u16, value: u8) -> Result<(), MemoryError> {
+        if let Some(attached) = self.find_device(pointer) {
+            attached.device.borrow_mut().write(pointer - attached.range.start(), value);
+            return Ok(());
+        }
+        match self.resolve(pointer) {
+            (address, Access::ReadWrite) => {
+                let physical = self.bank_selector.physical(address);
+                self.mapping[physical] = value;
+            },
+            (_, Access::OpenBus) => {},
+            (_, Access::ReadOnly) => match self.rom_write_policy {
+                RomWritePolicy::Ignore => {},
+                RomWritePolicy::Record => {
+                    self.ignored_rom_writes += 1;
+                    self.last_ignored_rom_write = Some(MemoryError::RomWrite { address: pointer, value });
+                },
+                RomWritePolicy::Error => return Err(MemoryError::RomWrite { address: pointer, value }),
+            },
+        }
+        Ok(())
     }
 
-    pub fn new(memory: Vec<u8>) -> Memory {
+    /// Error `set_byte_at_offset` would fail with, without writing anything.
+    pub fn check_write(&self, pointer: u16, value: u8) -> Result<(), MemoryError> {
+        if self.find_device(pointer).is_some() || self.rom_write_policy != RomWritePolicy::Error {
+            return Ok(());
+        }
+        match self.resolve(pointer) {
+            (_, Access::ReadOnly) => Err(MemoryError::RomWrite { address: pointer, value }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Zeroed read-write memory without any images.
+    pub fn new() -> Memory {
         Memory {
-            mapping: memory,
+            mapping: vec![0; N_BYTES],
+            images: Vec::new(),
+            bank_images: Vec::new(),
+            regions: Vec::new(),
+            rom_write_policy: RomWritePolicy::Ignore,
+            devices: Vec::new(),
+            device_pages: vec![false; N_BYTES / PAGE_SIZE],
+            bank_selector: BankSelector::new(1, N_BYTES),
+            ignored_rom_writes: 0,
+            last_ignored_rom_write: None,
         }
     }
+
+    /// Number of writes to read-only regions dropped under `RomWritePolicy::Record`.
+    pub fn ignored_rom_writes(&self) -> u64 {
+        self.ignored_rom_writes
+    }
+
+    /// Most recent write counted by `ignored_rom_writes`.
+    pub fn last_ignored_rom_write(&self) -> Option<MemoryError> {
+        self.last_ignored_rom_write
+    }
+
+    /// Routes CPU reads and writes within `range` to `device`, ahead of the memory map.
+    /// Devices attached later take precedence over earlier ones they overlap.
+    pub fn attach_device(&mut self, range: RangeInclusive<u16>, device: Box<dyn MemoryMappedDevice>) {
+        for page in (*range.start() as usize / PAGE_SIZE)..=(*range.end() as usize / PAGE_SIZE) {
+            self.device_pages[page] = true;
+        }
+        self.devices.push(AttachedDevice { range, device: RefCell::new(device) });
+    }
+
+    // Address actually accessed and the rules for accessing it, after following mirrors
+    fn resolve(&self, pointer: u16) -> (u16, Access) {
+        if self.regions.is_empty() {
+            return (pointer, Access::ReadWrite);
+        }
+        let address = match self.find_region(pointer, true) {
+            Some(Region { range, kind: RegionKind::Mirror { source, size } }) => {
+                let offset = (pointer - range.start()) as usize % size;
+                source.wrapping_add(offset as u16)
+            },
+            _ => pointer,
+        };
+        match self.find_region(address, false) {
+            Some(Region { kind: RegionKind::Plain(access), .. }) => (address, *access),
+            _ => (address, Access::ReadWrite),
+        }
+    }
+
+    fn find_device(&self, pointer: u16) -> Option<&AttachedDevice> {
+        if !self.device_pages[pointer as usize / PAGE_SIZE] {
+            return None;
+        }
+        self.devices.iter().rev().find(|attached| attached.range.contains(&pointer))
+    }
+
+    // Image split at the bank windows, each piece along with `bank` or, without one, the bank
+    // currently selected there. Fails if the image runs past 0xFFFF or a piece overlaps one
+    // `loaded` into the same bank.
+    fn place_image(&self, origin: u16, size: usize, bank: Option<usize>, loaded: &[(usize, Range<usize>)])
+                   -> Result<Vec<(usize, Range<usize>)>, LoadError> {
+        let image = origin as usize..origin as usize + size;
+        if image.end > N_BYTES {
+            return Err(LoadError::Oversize { origin, size });
+        }
+        let window_size = self.bank_selector.window_size();
+        let mut pieces = Vec::new();
+        let mut start = image.start;
+        while start < image.end {
+            let window = start / window_size;
+            let end = image.end.min((window + 1) * window_size);
+            pieces.push((bank.unwrap_or_else(|| self.bank_selector.selected(window)), start..end));
+            start = end;
+        }
+        for (bank, piece) in &pieces {
+            let overlapping = loaded.iter().find(|(loaded_bank, loaded)| {
+                loaded_bank == bank && piece.start < loaded.end && loaded.start < piece.end
+            });
+            if let Some((_, loaded)) = overlapping {
+                return Err(LoadError::Overlap { image, loaded: loaded.clone() });
+            }
+        }
+        Ok(pieces)
+    }
+
+    fn write_image(&mut self, origin: u16, image: &[u8], pieces: Vec<(usize, Range<usize>)>) {
+        let range = origin as usize..origin as usize + image.len();
+        for (address, value) in range.clone().zip(image) {
+            let physical = self.bank_selector.physical(address as u16);
+            self.mapping[physical] = *value;
+        }
+        self.images.push(range);
+        self.bank_images.extend(pieces);
+    }
+
+    fn find_region(&self, pointer: u16, include_mirrors: bool) -> Option<&Region> {
+        self.regions.iter().rev().find(|region| {
+            region.range.contains(&pointer) && (include_mirrors || matches!(region.kind, RegionKind::Plain(_)))
+        })
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
 }
 
-fn read_file(path: &Path) -> Vec<u8> {
-    let mut file = File::open(path).unwrap();
+fn read_file(path: &Path) -> Result<Vec<u8>, LoadError> {
+    let io_error = |error: io::Error| match error.kind() {
+        io::ErrorKind::NotFound => LoadError::NotFound { path: path.to_path_buf() },
+        _ => LoadError::Io { path: path.to_path_buf(), error },
+    };
+    let mut file = File::open(path).map_err(io_error)?;
     let mut data = Vec::new();
-    file.read_to_end(&mut data);
-    return data;
+    file.read_to_end(&mut data).map_err(io_error)?;
+    Ok(data)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::rc::Rc;
+    use proptest::prelude::*;
+
+    // Contents of tests/fixtures/rom.bin: MVI A, 0x42; STA 0x2000; HLT
+    const ROM: [u8; 6] = [0x3E, 0x42, 0x32, 0x00, 0x20, 0x76];
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    // Origins worth probing: both ends of the address space, the last page and anything else
+    fn boundary_address() -> impl Strategy<Value = u16> {
+        prop_oneof![Just(0x0000), Just(0xFFFF), 0xFF00..=0xFFFFu16, any::<u16>()]
+    }
 
     #[test]
     fn test_read_file() {
-        let mut tmp_dir = home_dir().unwrap();
-        tmp_dir.push(".bash_history");
-        assert_ne!(read_file(tmp_dir.as_path()).len(), 0)
+        assert_eq!(read_file(&fixture("rom.bin")).unwrap(), ROM);
+        assert!(read_file(&fixture("empty.bin")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_load_rom() {
+        let memory = Memory::load_rom(&fixture("rom.bin"), 0x0100).unwrap();
+        assert_eq!(memory.fetch_bytes_at_offset(0x0100, ROM.len()), ROM);
+        assert!(!memory.is_in_image(0x00FF) && memory.is_in_image(0x0105) && !memory.is_in_image(0x0106));
+
+        let memory = Memory::load_rom(&fixture("empty.bin"), 0xFFFF).unwrap();
+        assert!(!memory.is_in_image(0xFFFF));
     }
 
     #[test]
-    fn test_load_rom_not_empty() {
-        let mut tmp_dir = home_dir().unwrap();
-        tmp_dir.push(".bash_history");
-        let memory = Memory::load_rom(tmp_dir.as_path());
-        assert_ne!(memory.fetch_byte_at_offset(0), 0)
+    fn test_load_file_detects_format() {
+        let mut memory = Memory::new();
+        assert_eq!(memory.load_file(&fixture("rom.hex"), 0x2000).unwrap(), Some(0x0100));
+        assert_eq!(memory.export_binary(0x0100..=0x0105), ROM);
+        assert!(matches!(memory.load_file(&fixture("rom.bin"), 0x0105), Err(LoadError::Overlap { .. })));
+        assert_eq!(memory.load_file(&fixture("rom.bin"), 0x0106).unwrap(), None);
+    }
+
+    #[test]
+    fn test_load_as() {
+        // LDA 0x4242; HLT, which happens to start like an Intel HEX record
+        let rom = b":BBv";
+        let mut memory = Memory::new();
+        assert_eq!(memory.load_detected(rom, 0x0000).unwrap(), None);
+        assert_eq!(memory.export_binary(0x0000..=0x0003), rom);
+        assert!(matches!(memory.load_as(Format::from_name("ihex").unwrap(), rom, 0x1000),
+            Err(LoadError::Format(FormatError::InvalidLength { line: 1 }))));
+
+        let hex = std::fs::read(fixture("rom.hex")).unwrap();
+        assert_eq!(memory.load_as(Format::Raw, &hex, 0x2000).unwrap(), None);
+        assert_eq!(memory.export_binary(0x2000..=0x2000 + hex.len() as u16 - 1), hex);
+        assert!(matches!(memory.load_as(Format::from_name("srec").unwrap(), b"S1\n\xff", 0),
+            Err(LoadError::Format(FormatError::InvalidDigit { line: 2 }))));
+        assert_eq!(memory.load_file_as(&fixture("rom.hex"), 0, Format::Detect).unwrap(), Some(0x0100));
     }
 
     #[test]
     fn test_set_byte() {
-        let mut tmp_dir = home_dir().unwrap();
-        tmp_dir.push(".bash_history");
-        let mut memory = Memory::load_rom(tmp_dir.as_path());
+        let mut memory = Memory::with_image(0x0000, &ROM).unwrap();
         let offset = 0;
         let val = 20;
-        memory.set_byte_at_offset(offset, val);
+        memory.set_byte_at_offset(offset, val).unwrap();
         assert_eq!(memory.fetch_byte_at_offset(offset), val)
     }
-}
\ No newline at end of file
+
+    proptest! {
+        #[test]
+        fn prop_set_then_fetch(address in boundary_address(), value: u8) {
+            let mut memory = Memory::new();
+            memory.set_byte_at_offset(address, value).unwrap();
+            prop_assert_eq!(memory.fetch_byte_at_offset(address), value);
+            prop_assert_eq!(memory.fetch_byte_at_offset(address.wrapping_add(1)), 0x00);
+            prop_assert_eq!(memory.fetch_byte_at_offset(address.wrapping_sub(1)), 0x00);
+        }
+
+        #[test]
+        fn prop_load_within_address_space(
+            origin in boundary_address(),
+            image in prop::collection::vec(any::<u8>(), 0..=512),
+        ) {
+            let mut memory = Memory::new();
+            let fits = origin as usize + image.len() <= N_BYTES;
+            match memory.load_image(origin, &image) {
+                Ok(()) => {
+                    prop_assert!(fits);
+                    prop_assert_eq!(memory.fetch_bytes_at_offset(origin, image.len()), image.clone());
+                    prop_assert_eq!(memory.images().first(), Some(&(origin as usize..origin as usize + image.len())));
+                },
+                Err(error) => {
+                    prop_assert!(!fits);
+                    let is_oversize = matches!(error, LoadError::Oversize { .. });
+                    prop_assert!(is_oversize);
+                    prop_assert!(memory.images().is_empty());
+                },
+            }
+            // Nothing wraps around to the bottom of memory
+            if origin != 0x0000 {
+                prop_assert_eq!(memory.fetch_byte_at_offset(0x0000), 0x00);
+            }
+        }
+
+        #[test]
+        fn prop_images_do_not_overlap(first in boundary_address(), second in boundary_address(), size in 1..=16usize) {
+            prop_assume!(first as usize + size <= N_BYTES && second as usize + size <= N_BYTES);
+            let mut memory = Memory::new();
+            memory.load_image(first, &vec![0x01; size]).unwrap();
+            let overlaps = (first as usize) < second as usize + size && (second as usize) < first as usize + size;
+            let loaded = memory.load_image(second, &vec![0x02; size]);
+            prop_assert_eq!(loaded.is_err(), overlaps);
+            prop_assert_eq!(memory.fetch_byte_at_offset(first), 0x01);
+        }
+    }
+
+    #[test]
+    fn test_full_address_space() {
+        let mut memory = Memory::with_image(0x0000, &[0x01, 0x02]).unwrap();
+        assert_eq!(memory.fetch_byte_at_offset(0xFFFF), 0);
+        memory.set_byte_at_offset(0xFFFF, 0x12).unwrap();
+        assert_eq!(memory.fetch_byte_at_offset(0xFFFF), 0x12);
+    }
+
+    #[test]
+    fn test_load_image_at_origin() {
+        let mut memory = Memory::new();
+        memory.load_image(0x1800, &[0x01, 0x02]).unwrap();
+        memory.load_image(0xFFFF, &[0x03]).unwrap();
+        assert_eq!(memory.fetch_bytes_at_offset(0x17FF, 4), vec![0x00, 0x01, 0x02, 0x00]);
+        assert_eq!(memory.fetch_byte_at_offset(0xFFFF), 0x03);
+        assert_eq!(memory.images(), &[0x1800..0x1802, 0xFFFF..0x10000]);
+        assert!(!memory.is_in_image(0x17FF));
+        assert!(memory.is_in_image(0x1801));
+        assert!(!memory.is_in_image(0x1802));
+        assert!(memory.is_in_image(0xFFFF));
+    }
+
+    #[test]
+    fn test_load_image_past_end() {
+        let mut memory = Memory::new();
+        let error = memory.load_image(0xFFFF, &[0x01, 0x02]).unwrap_err();
+        assert!(matches!(error, LoadError::Oversize { origin: 0xFFFF, size: 2 }));
+        assert_eq!(error.to_string(), "image of 2 bytes does not fit at 0xffff");
+        assert!(matches!(memory.load_image_into_bank(0, 0xFFFF, &[0x01, 0x02]), Err(LoadError::Oversize { .. })));
+        assert_eq!(memory.fetch_byte_at_offset(0xFFFF), 0x00);
+        assert!(memory.images().is_empty());
+    }
+
+    #[test]
+    fn test_load_image_overlap() {
+        let mut memory = Memory::with_image(0x0100, &[0x01, 0x02, 0x03]).unwrap();
+        let error = memory.load_image(0x0000, &[0x04; 0x0101]).unwrap_err();
+        assert!(matches!(&error, LoadError::Overlap { image, loaded } if *image == (0x0000..0x0101) && *loaded == (0x0100..0x0103)));
+        assert_eq!(error.to_string(), "image at 0x0000-0x0100 overlaps one loaded at 0x0100-0x0102");
+        assert_eq!(memory.fetch_byte_at_offset(0x0000), 0x00);
+        memory.load_image(0x0000, &[0x04; 0x0100]).unwrap();
+        memory.load_image(0x0103, &[0x05]).unwrap();
+        memory.load_image(0x0103, &[]).unwrap();
+    }
+
+    #[test]
+    fn test_load_segments_all_or_nothing() {
+        let mut memory = Memory::new();
+        let error = memory.load_intel_hex(":0100000001FE\n:020000000203F9\n:00000001FF\n").unwrap_err();
+        assert!(matches!(error, LoadError::Overlap { .. }));
+        assert_eq!(memory.fetch_byte_at_offset(0x0000), 0x00);
+        assert!(memory.images().is_empty());
+    }
+
+    #[test]
+    fn test_load_file_errors() {
+        let missing = Path::new("/nonexistent/rom.bin");
+        let error = Memory::load_rom(missing, 0).unwrap_err();
+        assert!(matches!(&error, LoadError::NotFound { path } if path == missing));
+        assert_eq!(error.to_string(), "/nonexistent/rom.bin: no such file");
+
+        let directory = std::env::temp_dir();
+        let error = Memory::new().load_file(&directory, 0).unwrap_err();
+        assert!(matches!(error, LoadError::Io { .. }));
+        assert!(error.source().is_some());
+    }
+
+    #[test]
+    fn test_check_write() {
+        let mut memory = Memory::builder()
+            .read_only(0x0000..=0x00FF)
+            .mirror(0x8000..=0x80FF, 0x0000..=0x00FF)
+            .rom_write_policy(RomWritePolicy::Error)
+            .build();
+        assert_eq!(memory.check_write(0x0010, 0x12), Err(MemoryError::RomWrite { address: 0x0010, value: 0x12 }));
+        assert_eq!(memory.check_write(0x8010, 0x12), Err(MemoryError::RomWrite { address: 0x8010, value: 0x12 }));
+        assert_eq!(memory.check_write(0x0100, 0x12), Ok(()));
+        memory.rom_write_policy = RomWritePolicy::Ignore;
+        assert_eq!(memory.check_write(0x0010, 0x12), Ok(()));
+    }
+
+    #[test]
+    fn test_read_only_region() {
+        let mut memory = Memory::builder().read_only(0x0000..=0x1FFF).build();
+        memory.load_image(0x0000, &[0x12]).unwrap();
+        assert_eq!(memory.set_byte_at_offset(0x0000, 0x34), Ok(()));
+        assert_eq!(memory.fetch_byte_at_offset(0x0000), 0x12);
+        assert_eq!(memory.set_byte_at_offset(0x2000, 0x34), Ok(()));
+        assert_eq!(memory.fetch_byte_at_offset(0x2000), 0x34);
+    }
+
+    #[test]
+    fn test_rom_write_policy_error() {
+        let mut memory = Memory::builder()
+            .read_only(0x0000..=0x1FFF)
+            .rom_write_policy(RomWritePolicy::Error)
+            .build();
+        let error = MemoryError::RomWrite { address: 0x1FFF, value: 0x34 };
+        assert_eq!(memory.set_byte_at_offset(0x1FFF, 0x34), Err(error));
+        assert_eq!(memory.fetch_byte_at_offset(0x1FFF), 0x00);
+        assert_eq!(error.to_string(), "write of 0x34 to ROM at 0x1fff");
+    }
+
+    #[test]
+    fn test_rom_write_policy_record() {
+        let mut memory = Memory::builder()
+            .read_only(0x0000..=0x1FFF)
+            .rom_write_policy(RomWritePolicy::Record)
+            .build();
+        assert_eq!(memory.set_byte_at_offset(0x2000, 0x12), Ok(()));
+        assert_eq!(memory.ignored_rom_writes(), 0);
+        assert_eq!(memory.set_byte_at_offset(0x0010, 0x12), Ok(()));
+        assert_eq!(memory.set_byte_at_offset(0x0020, 0x34), Ok(()));
+        assert_eq!(memory.fetch_byte_at_offset(0x0020), 0x00);
+        assert_eq!(memory.ignored_rom_writes(), 2);
+        assert_eq!(memory.last_ignored_rom_write(), Some(MemoryError::RomWrite { address: 0x0020, value: 0x34 }));
+    }
+
+    #[test]
+    fn test_mirrored_region() {
+        let mut memory = Memory::builder()
+            .read_only(0x0000..=0x1FFF)
+            .read_write(0x2000..=0x3FFF)
+            .mirror(0x4000..=0xFFFF, 0x2000..=0x3FFF)
+            .mirror(0xE000..=0xEFFF, 0x0000..=0x00FF)
+            .build();
+        memory.set_byte_at_offset(0x4001, 0x12).unwrap();
+        assert_eq!(memory.fetch_byte_at_offset(0x2001), 0x12);
+        assert_eq!(memory.fetch_byte_at_offset(0x6001), 0x12);
+        memory.set_byte_at_offset(0x3FFF, 0x34).unwrap();
+        assert_eq!(memory.fetch_byte_at_offset(0xFFFF), 0x34);
+
+        memory.load_image(0x0010, &[0x56]).unwrap();
+        assert_eq!(memory.fetch_byte_at_offset(0xE110), 0x56);
+        memory.set_byte_at_offset(0xE010, 0x78).unwrap();
+        assert_eq!(memory.fetch_byte_at_offset(0x0010), 0x56);
+    }
+
+    #[test]
+    fn test_open_bus_region() {
+        let mut memory = Memory::builder().open_bus(0xC000..=0xFFFF).build();
+        memory.set_byte_at_offset(0xC000, 0x12).unwrap();
+        assert_eq!(memory.fetch_byte_at_offset(0xC000), 0xFF);
+        assert_eq!(memory.fetch_byte_at_offset(0xBFFF), 0x00);
+    }
+
+    // Offset of each access, with the value for writes
+    type Accesses = Rc<RefCell<Vec<(u16, Option<u8>)>>>;
+
+    #[derive(Debug, Default)]
+    struct TestDevice {
+        accesses: Accesses,
+    }
+
+    impl MemoryMappedDevice for TestDevice {
+        fn read(&mut self, offset: u16) -> u8 {
+            self.accesses.borrow_mut().push((offset, None));
+            0x42
+        }
+
+        fn write(&mut self, offset: u16, value: u8) {
+            self.accesses.borrow_mut().push((offset, Some(value)));
+        }
+    }
+
+    #[test]
+    fn test_memory_mapped_device() {
+        let accesses = Accesses::default();
+        let mut memory = Memory::builder().read_only(0x0000..=0x3FFF).build();
+        memory.attach_device(0x2400..=0x2401, Box::new(TestDevice { accesses: accesses.clone() }));
+        assert_eq!(memory.fetch_byte_at_offset(0x2401), 0x42);
+        assert_eq!(memory.set_byte_at_offset(0x2400, 0x12), Ok(()));
+        assert_eq!(memory.fetch_byte_at_offset(0x2402), 0x00);
+        memory.set_byte_at_offset(0x23FF, 0x34).unwrap();
+        assert_eq!(*accesses.borrow(), vec![(0x0001, None), (0x0000, Some(0x12))]);
+        assert_eq!(memory.fetch_bytes_at_offset(0x2400, 1), vec![0x00]);
+    }
+
+    #[test]
+    fn test_bank_switching() {
+        let mut memory = Memory::builder().banks(4, 0x4000).build();
+        let selector = memory.bank_selector();
+        assert_eq!(selector.window_count(), 4);
+        memory.load_image(0x0000, &[0x01]).unwrap();
+        memory.load_image_into_bank(2, 0x0000, &[0x02]).unwrap();
+        memory.set_byte_at_offset(0xC000, 0x03).unwrap();
+
+        selector.select(0, 2);
+        selector.select(3, 1);
+        assert_eq!(memory.fetch_byte_at_offset(0x0000), 0x02);
+        assert_eq!(memory.fetch_byte_at_offset(0xC000), 0x00);
+        memory.set_byte_at_offset(0x4000, 0x04).unwrap();
+
+        selector.select(0, 4);
+        selector.select(3, 0);
+        assert_eq!(selector.selected(0), 0);
+        assert_eq!(memory.fetch_byte_at_offset(0x0000), 0x01);
+        assert_eq!(memory.fetch_byte_at_offset(0x4000), 0x04);
+        assert_eq!(memory.fetch_byte_at_offset(0xC000), 0x03);
+    }
+
+    #[test]
+    fn test_bank_images() {
+        let mut memory = Memory::builder().banks(2, 0x4000).build();
+        let selector = memory.bank_selector();
+        memory.load_image_into_bank(1, 0x4000, &[0x01, 0x02]).unwrap();
+        assert!(!memory.is_in_image(0x4000));
+        selector.select(1, 1);
+        assert!(memory.is_in_image(0x4001) && !memory.is_in_image(0x4002));
+        assert!(memory.images().is_empty());
+
+        memory.load_image_into_bank(0, 0x4000, &[0x03]).unwrap();
+        let error = memory.load_image_into_bank(1, 0x4001, &[0x04]).unwrap_err();
+        assert!(matches!(error, LoadError::Overlap { .. }));
+        let error = memory.load_image_into_bank(2, 0x0000, &[0x05]).unwrap_err();
+        assert!(matches!(error, LoadError::NoSuchBank { bank: 2 }));
+        assert_eq!(error.to_string(), "no bank 2");
+    }
+
+    #[test]
+    fn test_images_follow_selected_bank() {
+        let mut memory = Memory::builder().banks(2, 0x4000).build();
+        let selector = memory.bank_selector();
+        memory.load_image(0x3FFF, &[0x01, 0x02]).unwrap();
+        selector.select(1, 1);
+        assert!(memory.is_in_image(0x3FFF));
+        assert!(!memory.is_in_image(0x4000));
+        memory.load_image(0x4000, &[0x03]).unwrap();
+        assert!(memory.is_in_image(0x4000));
+        assert!(matches!(memory.load_image_into_bank(0, 0x4000, &[0x04]), Err(LoadError::Overlap { .. })));
+
+        selector.select(1, 0);
+        assert!(memory.is_in_image(0x4000));
+        assert_eq!(memory.fetch_byte_at_offset(0x4000), 0x02);
+        assert!(matches!(memory.load_image(0x4000, &[0x05]), Err(LoadError::Overlap { .. })));
+        assert_eq!(memory.images().len(), 2);
+    }
+
+    #[test]
+    fn test_bank_select_wraps_around() {
+        let memory = Memory::builder().banks(4, 0x4000).build();
+        let selector = memory.bank_selector();
+        selector.select(5, 6);
+        assert_eq!(selector.selected(1), 2);
+        assert_eq!(selector.selected(5), 2);
+    }
+
+    #[test]
+    fn test_load_intel_hex() {
+        let mut memory = Memory::new();
+        let start = memory.load_intel_hex(":020100003E01BE\n:0400000500000100F6\n:00000001FF\n");
+        assert_eq!(start.unwrap(), Some(0x0100));
+        assert_eq!(memory.fetch_bytes_at_offset(0x0100, 2), vec![0x3E, 0x01]);
+        assert!(memory.is_in_image(0x0101) && !memory.is_in_image(0x0102));
+
+        let error = memory.load_intel_hex(":0100000001FE\n:0100000001FF\n:00000001FF\n");
+        assert!(matches!(
+            error,
+            Err(LoadError::Format(FormatError::ChecksumMismatch { line: 2, expected: 0xFE, actual: 0xFF })),
+        ));
+        assert_eq!(memory.fetch_byte_at_offset(0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_load_detected() {
+        let mut memory = Memory::new();
+        assert_eq!(memory.load_detected(b"S1040100AA50\nS9030100FB\n", 0x2000).unwrap(), Some(0x0100));
+        assert_eq!(memory.load_detected(b"%0A81741000\n", 0x2000).unwrap(), Some(0x1000));
+        assert_eq!(memory.load_detected(&[0xC3, 0x00, 0x01], 0x2000).unwrap(), None);
+        assert_eq!(memory.fetch_byte_at_offset(0x0100), 0xAA);
+        assert_eq!(memory.fetch_bytes_at_offset(0x2000, 3), vec![0xC3, 0x00, 0x01]);
+        assert!(matches!(
+            memory.load_detected(b":0100000001FE\n:00000001FE\n", 0x2000),
+            Err(LoadError::Format(FormatError::ChecksumMismatch { line: 2, expected: 0xFF, actual: 0xFE })),
+        ));
+    }
+
+    #[test]
+    fn test_export_binary() {
+        let mut memory = Memory::with_image(0xFFFE, &[0x01, 0x02]).unwrap();
+        memory.set_byte_at_offset(0x0000, 0x03).unwrap();
+        assert_eq!(memory.export_binary(0xFFFE..=0xFFFF), vec![0x01, 0x02]);
+        assert_eq!(memory.export_binary(0x0000..=0xFFFF).len(), N_BYTES);
+
+        let mut copy = Memory::new();
+        copy.load_image(0x0000, &memory.export_binary(0x0000..=0xFFFF)).unwrap();
+        assert_eq!(copy.export_binary(0x0000..=0xFFFF), memory.export_binary(0x0000..=0xFFFF));
+    }
+
+    #[test]
+    fn test_export_intel_hex_round_trip() {
+        let mut memory = Memory::new();
+        for address in 0xFF00..=0xFFFF {
+            memory.set_byte_at_offset(address, address as u8 ^ 0x5A).unwrap();
+        }
+        let exported = memory.export_intel_hex(0xFF00..=0xFFFF, Some(0xFF00));
+        let mut copy = Memory::new();
+        assert_eq!(copy.load_intel_hex(&exported).unwrap(), Some(0xFF00));
+        assert_eq!(copy.export_binary(0xFF00..=0xFFFF), memory.export_binary(0xFF00..=0xFFFF));
+        assert!(copy.is_in_image(0xFFFF));
+    }
+}
@@ -0,0 +1,11 @@
+use std::fmt::Debug;
+
+/// Peripheral answering CPU accesses to a range of addresses, attached with
+/// `Memory::attach_device`. Offsets are relative to the start of that range.
+pub trait MemoryMappedDevice: Debug {
+    /// Value the CPU reads at `offset`.
+    fn read(&mut self, offset: u16) -> u8;
+
+    /// Handles the CPU writing `value` at `offset`.
+    fn write(&mut self, offset: u16, value: u8);
+}
@@ -1,27 +1,41 @@
-use std::u8;
 use std::fmt::Debug;
 
-#[derive(Debug)]
+/// General purpose registers and the accumulator.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct Registers {
+    /// Accumulator, A.
     pub acc: u8,
+    /// Register B.
     pub b: u8,
+    /// Register C.
     pub c: u8,
+    /// Register D.
     pub d: u8,
+    /// Register E.
     pub e: u8,
+    /// Register H.
     pub h: u8,
+    /// Register L.
     pub l: u8,
 }
 
-#[derive(Debug)]
+/// Condition flags, stored in the lower byte of PSW.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct Flags {
+    /// Z, set when a result is zero.
     pub zero: bool,
+    /// S, bit 7 of a result.
     pub sign: bool,
+    /// P, set when a result has an even number of one bits.
     pub parity: bool,
+    /// CY, carry out of bit 7 or borrow into it.
     pub carry: bool,
+    /// AC, carry out of bit 3, used by DAA.
     pub aux_carry: bool,
 }
 
 impl Registers {
+    /// All registers cleared.
     pub fn new() -> Registers {
         Registers {
             acc: 0,
@@ -34,39 +48,42 @@ impl Registers {
         }
     }
 
+    /// H and L as a word, H being the high byte.
     pub fn get_hl(&self) -> u16 {
-        (((self.h as u16) << 8) | (self.l as u16))
+        ((self.h as u16) << 8) | (self.l as u16)
     }
 
+    /// D and E as a word, D being the high byte.
     pub fn get_de(&self) -> u16 {
-        (((self.d as u16) << 8) | (self.e as u16))
+        ((self.d as u16) << 8) | (self.e as u16)
     }
 
+    /// B and C as a word, B being the high byte.
     pub fn get_bc(&self) -> u16 {
-        (((self.b as u16) << 8) | (self.c as u16))
+        ((self.b as u16) << 8) | (self.c as u16)
     }
 
+    /// Sets B to the high and C to the low byte of `value`.
     pub fn set_bc(&mut self, value: u16) {
         self.b = (value >> 8) as u8;
         self.c = value as u8;
     }
 
+    /// Sets D to the high and E to the low byte of `value`.
     pub fn set_de(&mut self, value: u16) {
-        println!("{}", value);
-        println!("{}", value as u8);
-        println!("{}", (value >> 8) as u8);
         self.d = (value >> 8) as u8;
         self.e = value as u8;
     }
 
+    /// Sets H to the high and L to the low byte of `value`.
     pub fn set_hl(&mut self, value: u16) {
         self.h = (value >> 8) as u8;
         self.l = value as u8;
     }
 }
 
-// TODO: AUX CARRY
 impl Flags {
+    /// All flags reset.
     pub fn new() -> Flags {
         Flags {
             zero: false,
@@ -77,38 +94,74 @@ impl Flags {
         }
     }
 
-    pub fn set_zero(&mut self, value: u16) {
-        self.zero = value.count_ones() == 0;
+    pub(crate) fn set_zero(&mut self, value: u16) {
+        self.zero = (value & 0xFF).count_ones() == 0;
     }
 
-    pub fn set_parity(&mut self, value: u16) {
-        self.parity = value.count_ones() % 2 == 0;
+    pub(crate) fn set_parity(&mut self, value: u16) {
+        self.parity = (value & 0xFF).count_ones().is_multiple_of(2);
     }
 
-    pub fn set_sign(&mut self, value: u16) {
+    pub(crate) fn set_sign(&mut self, value: u16) {
         self.sign = (value & 0x80) != 0;
     }
 
-    pub fn set_carry(&mut self, value: u16) {
+    pub(crate) fn set_carry(&mut self, value: u16) {
         self.carry = value > 0xFF;
     }
 
-    pub fn set_carry_on_double(&mut self, value: u32) {
+    pub(crate) fn set_carry_on_double(&mut self, value: u32) {
         self.carry = value > 0xFFFF;
     }
 
-    pub fn set_all(&mut self, value: u16) {
+    pub(crate) fn set_aux_carry_on_add(&mut self, first: u8, second: u8, carry: bool) {
+        self.aux_carry = (first & 0x0F) + (second & 0x0F) + (carry as u8) > 0x0F;
+    }
+
+    // Subtraction is an addition of the two's complement, AC is the carry out of its bit 3
+    pub(crate) fn set_aux_carry_on_subtract(&mut self, minuend: u8, subtrahend: u8, borrow: bool) {
+        self.aux_carry = (minuend & 0x0F) + (!subtrahend & 0x0F) + (!borrow as u8) > 0x0F;
+    }
+
+    pub(crate) fn set_aux_carry_on_increment(&mut self, result: u8) {
+        self.aux_carry = result & 0x0F == 0;
+    }
+
+    pub(crate) fn set_aux_carry_on_decrement(&mut self, result: u8) {
+        self.aux_carry = result & 0x0F != 0x0F;
+    }
+
+    pub(crate) fn set_all(&mut self, value: u16) {
         self.set_zero(value);
         self.set_sign(value);
         self.set_parity(value);
         self.set_carry(value);
     }
 
-    pub fn set_single_registry_operation_flags(&mut self, value: u16) {
+    pub(crate) fn set_single_registry_operation_flags(&mut self, value: u16) {
         self.set_zero(value);
         self.set_sign(value);
         self.set_parity(value);
     }
+
+    /// Flags as laid out in the lower byte of PSW, bit 1 always being set.
+    pub fn as_byte(&self) -> u8 {
+        ((self.sign as u8) << 7) |
+        ((self.zero as u8) << 6) |
+        ((self.aux_carry as u8) << 4) |
+        ((self.parity as u8) << 2) |
+        0b00000010 |
+        (self.carry as u8)
+    }
+
+    /// Sets the flags from the lower byte of PSW, ignoring the unused bits.
+    pub fn set_from_byte(&mut self, value: u8) {
+        self.sign = value & 0b10000000 != 0;
+        self.zero = value & 0b01000000 != 0;
+        self.aux_carry = value & 0b00010000 != 0;
+        self.parity = value & 0b00000100 != 0;
+        self.carry = value & 0b00000001 != 0;
+    }
 }
 
 #[cfg(test)]
@@ -143,6 +196,9 @@ mod tests {
 
         flags.set_all(0);
         assert!(flags.zero);
+
+        flags.set_all(0x100);
+        assert!(flags.zero);
     }
 
     #[test]
@@ -152,7 +208,7 @@ mod tests {
         assert!(flags.sign);
 
         flags.set_all(0b01011001);
-        assert!(flags.sign)
+        assert!(!flags.sign)
     }
 
     #[test]
@@ -163,6 +219,9 @@ mod tests {
 
         flags.set_all(0b10011101);
         assert!(!flags.parity);
+
+        flags.set_all(0b110011001);
+        assert!(flags.parity);
     }
 
     #[test]
@@ -171,4 +230,61 @@ mod tests {
         flags.set_all(0b110011001);
         assert!(flags.carry);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_set_aux_carry_on_add() {
+        let mut flags = Flags::new();
+        flags.set_aux_carry_on_add(0x08, 0x08, false);
+        assert!(flags.aux_carry);
+
+        flags.set_aux_carry_on_add(0x07, 0x08, false);
+        assert!(!flags.aux_carry);
+
+        flags.set_aux_carry_on_add(0x07, 0x08, true);
+        assert!(flags.aux_carry);
+    }
+
+    #[test]
+    fn test_set_aux_carry_on_subtract() {
+        let mut flags = Flags::new();
+        flags.set_aux_carry_on_subtract(0x10, 0x01, false);
+        assert!(!flags.aux_carry);
+
+        flags.set_aux_carry_on_subtract(0x12, 0x01, false);
+        assert!(flags.aux_carry);
+
+        flags.set_aux_carry_on_subtract(0x12, 0x02, true);
+        assert!(!flags.aux_carry);
+    }
+
+    #[test]
+    fn test_set_aux_carry_on_increment_and_decrement() {
+        let mut flags = Flags::new();
+        flags.set_aux_carry_on_increment(0x20);
+        assert!(flags.aux_carry);
+        flags.set_aux_carry_on_increment(0x21);
+        assert!(!flags.aux_carry);
+
+        flags.set_aux_carry_on_decrement(0x1f);
+        assert!(!flags.aux_carry);
+        flags.set_aux_carry_on_decrement(0x1e);
+        assert!(flags.aux_carry);
+    }
+
+    #[test]
+    fn test_flags_byte() {
+        let mut flags = Flags::new();
+        flags.sign = true;
+        flags.aux_carry = true;
+        flags.carry = true;
+        assert_eq!(flags.as_byte(), 0b10010011);
+
+        let mut restored = Flags::new();
+        restored.set_from_byte(0b01000110);
+        assert!(restored.zero);
+        assert!(restored.parity);
+        assert!(!restored.sign);
+        assert!(!restored.aux_carry);
+        assert!(!restored.carry);
+    }
+}
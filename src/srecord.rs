@@ -0,0 +1,120 @@
+use crate::loader::{address_of, append, decode_hex, set_start_address, FormatError, LoadedImage, Loader};
+
+/// Motorola S-records. Headers (S0) and record counts (S5, S6) are checked and skipped.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SRecord;
+
+impl Loader for SRecord {
+    fn name(&self) -> &'static str {
+        "Motorola S-record"
+    }
+
+    fn parse(&self, input: &str) -> Result<LoadedImage, FormatError> {
+        parse(input)
+    }
+
+    fn is_record(&self, text: &str) -> bool {
+        decode_record(text, 1).is_ok_and(|(record_type, _)| record_type != 4)
+    }
+}
+
+/// Parses S-record text up to the first termination record (S7, S8 or S9), which holds
+/// the start address.
+pub fn parse(input: &str) -> Result<LoadedImage, FormatError> {
+    let mut image = LoadedImage::default();
+    for (index, text) in input.lines().enumerate() {
+        let line = index + 1;
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        let (record_type, bytes) = decode_record(text, line)?;
+        let address_size = match record_type {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(FormatError::UnknownRecordType { line, record_type }),
+        };
+        if bytes.len() < address_size + 2 {
+            return Err(FormatError::InvalidLength { line });
+        }
+        let address = address_of(&bytes[1..1 + address_size]);
+        let data = &bytes[1 + address_size..bytes.len() - 1];
+        match record_type {
+            1..=3 => append(&mut image, address, data, line)?,
+            7..=9 => {
+                set_start_address(&mut image, address, line)?;
+                return Ok(image);
+            },
+            _ => {},
+        }
+    }
+    Err(FormatError::MissingEndOfFile)
+}
+
+// Record type along with the byte count, address, data and checksum, with the checksum verified
+fn decode_record(text: &str, line: usize) -> Result<(u8, Vec<u8>), FormatError> {
+    let digits = text.strip_prefix('S').ok_or(FormatError::MissingStartCode { line })?;
+    let record_type = digits.chars().next()
+        .ok_or(FormatError::InvalidLength { line })?
+        .to_digit(10)
+        .ok_or(FormatError::InvalidDigit { line })? as u8;
+    let bytes = decode_hex(&digits[1..], line)?;
+    if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+        return Err(FormatError::InvalidLength { line });
+    }
+    let (record, checksum) = bytes.split_at(bytes.len() - 1);
+    let expected = !record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    if expected != checksum[0] {
+        return Err(FormatError::ChecksumMismatch { line, expected, actual: checksum[0] });
+    }
+    Ok((record_type, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::Segment;
+
+    #[test]
+    fn test_parse() {
+        let input = "\
+S00F000068656C6C6F202020202000003C
+S1070100C30001B47F
+S104010401F5
+S20600020105767B
+S5030002FA
+S9030100FB
+S1040000FFFC
+";
+        let image = parse(input).unwrap();
+        assert_eq!(image.segments, vec![
+            Segment { origin: 0x0100, data: vec![0xC3, 0x00, 0x01, 0xB4, 0x01] },
+            Segment { origin: 0x0201, data: vec![0x05, 0x76] },
+        ]);
+        assert_eq!(image.start_address, Some(0x0100));
+    }
+
+    #[test]
+    fn test_parse_32_bit_records() {
+        let image = parse("S30700001000AABB83\nS70500000000FA\n").unwrap();
+        assert_eq!(image.segments, vec![Segment { origin: 0x1000, data: vec![0xAA, 0xBB] }]);
+        assert_eq!(image.start_address, Some(0x0000));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse(":030100C3FF"), Err(FormatError::MissingStartCode { line: 1 }));
+        assert_eq!(parse("SX030000FC"), Err(FormatError::InvalidDigit { line: 1 }));
+        assert_eq!(parse("S"), Err(FormatError::InvalidLength { line: 1 }));
+        assert_eq!(parse("S1040000FF"), Err(FormatError::InvalidLength { line: 1 }));
+        assert_eq!(parse("S10200FD"), Err(FormatError::InvalidLength { line: 1 }));
+        assert_eq!(
+            parse("S9030100FC"),
+            Err(FormatError::ChecksumMismatch { line: 1, expected: 0xFB, actual: 0xFC }),
+        );
+        assert_eq!(parse("S4030000FC"), Err(FormatError::UnknownRecordType { line: 1, record_type: 4 }));
+        assert_eq!(parse("S2060100000102F5"), Err(FormatError::AddressOutOfRange { line: 1 }));
+        assert_eq!(parse("S1040000FFFC\n"), Err(FormatError::MissingEndOfFile));
+    }
+}
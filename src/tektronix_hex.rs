@@ -0,0 +1,151 @@
+use crate::loader::{append, decode_hex, set_start_address, FormatError, LoadedImage, Loader};
+
+const DATA: u8 = 6;
+const TERMINATION: u8 = 8;
+const SYMBOL: u8 = 3;
+
+/// Tektronix extended hex. Symbol records are checked and skipped.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TektronixHex;
+
+impl Loader for TektronixHex {
+    fn name(&self) -> &'static str {
+        "Tektronix extended hex"
+    }
+
+    fn parse(&self, input: &str) -> Result<LoadedImage, FormatError> {
+        parse(input)
+    }
+
+    fn is_record(&self, text: &str) -> bool {
+        decode_record(text, 1).is_ok_and(|(record_type, _)| [DATA, TERMINATION, SYMBOL].contains(&record_type))
+    }
+}
+
+/// Parses Tektronix extended hex up to the first termination record, which holds the start
+/// address.
+pub fn parse(input: &str) -> Result<LoadedImage, FormatError> {
+    let mut image = LoadedImage::default();
+    for (index, text) in input.lines().enumerate() {
+        let line = index + 1;
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        let (record_type, body) = decode_record(text, line)?;
+        if record_type == SYMBOL {
+            continue;
+        }
+        let (address, data) = split_address(body, line)?;
+        match record_type {
+            DATA => append(&mut image, address, &decode_hex(data, line)?, line)?,
+            TERMINATION => {
+                set_start_address(&mut image, address, line)?;
+                return Ok(image);
+            },
+            _ => return Err(FormatError::UnknownRecordType { line, record_type }),
+        }
+    }
+    Err(FormatError::MissingEndOfFile)
+}
+
+// Record type and everything after the checksum, with the length and checksum verified
+fn decode_record(text: &str, line: usize) -> Result<(u8, &str), FormatError> {
+    let record = text.strip_prefix('%').ok_or(FormatError::MissingStartCode { line })?;
+    if !record.is_ascii() {
+        return Err(FormatError::InvalidDigit { line });
+    }
+    if record.len() < 5 {
+        return Err(FormatError::InvalidLength { line });
+    }
+    let header = decode_hex(&format!("{}{}", &record[..2], &record[3..5]), line)?;
+    if header[0] as usize != record.len() {
+        return Err(FormatError::InvalidLength { line });
+    }
+    let record_type = digit_value(record.as_bytes()[2]).filter(|value| *value < 16)
+        .ok_or(FormatError::InvalidDigit { line })?;
+    let mut expected: u8 = 0;
+    for character in record[..3].bytes().chain(record[5..].bytes()) {
+        let value = digit_value(character).ok_or(FormatError::InvalidDigit { line })?;
+        expected = expected.wrapping_add(value);
+    }
+    if expected != header[1] {
+        return Err(FormatError::ChecksumMismatch { line, expected, actual: header[1] });
+    }
+    Ok((record_type, &record[5..]))
+}
+
+// Address preceded by its number of digits, 0 standing for 16, and the data following it
+fn split_address(body: &str, line: usize) -> Result<(usize, &str), FormatError> {
+    let digits = match body.bytes().next().and_then(digit_value) {
+        Some(0) => 16,
+        Some(digits) if digits < 16 => digits as usize,
+        Some(_) => return Err(FormatError::InvalidDigit { line }),
+        None => return Err(FormatError::InvalidLength { line }),
+    };
+    if body.len() < digits + 1 {
+        return Err(FormatError::InvalidLength { line });
+    }
+    let address = u64::from_str_radix(&body[1..digits + 1], 16)
+        .map_err(|_| FormatError::InvalidDigit { line })?;
+    // Anything beyond 64 KiB is rejected later on, no need to keep the exact value
+    Ok((address.min(0x10000) as usize, &body[digits + 1..]))
+}
+
+// Value a character contributes to the checksum
+fn digit_value(character: u8) -> Option<u8> {
+    match character {
+        b'0'..=b'9' => Some(character - b'0'),
+        b'A'..=b'Z' => Some(character - b'A' + 10),
+        b'$' => Some(36),
+        b'%' => Some(37),
+        b'.' => Some(38),
+        b'_' => Some(39),
+        b'a'..=b'z' => Some(character - b'a' + 40),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::Segment;
+
+    #[test]
+    fn test_parse() {
+        let input = "\
+%1061C40100C30001
+%0C62940103B4
+%0B30A5hello
+%0E62E420002576
+%0A81741000
+%0781010
+";
+        let image = parse(input).unwrap();
+        assert_eq!(image.segments, vec![
+            Segment { origin: 0x0100, data: vec![0xC3, 0x00, 0x01, 0xB4] },
+            Segment { origin: 0x2000, data: vec![0x25, 0x76] },
+        ]);
+        assert_eq!(image.start_address, Some(0x1000));
+    }
+
+    #[test]
+    fn test_parse_long_addresses() {
+        let image = parse("%1661E80000010020202020\n%0781010\n").unwrap();
+        assert_eq!(image.segments, vec![Segment { origin: 0x0100, data: vec![0x20; 4] }]);
+        assert_eq!(image.start_address, Some(0x0000));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse(":0680E0"), Err(FormatError::MissingStartCode { line: 1 }));
+        assert_eq!(parse("%068"), Err(FormatError::InvalidLength { line: 1 }));
+        assert_eq!(parse("%0880E10"), Err(FormatError::InvalidLength { line: 1 }));
+        assert_eq!(parse("%0780G10"), Err(FormatError::InvalidDigit { line: 1 }));
+        assert_eq!(parse("%07810210"), Err(FormatError::InvalidLength { line: 1 }));
+        assert_eq!(parse("%0781F10"), Err(FormatError::ChecksumMismatch { line: 1, expected: 0x10, actual: 0x1F }));
+        assert_eq!(parse("%0F61E5100000102"), Err(FormatError::AddressOutOfRange { line: 1 }));
+        assert_eq!(parse("%0E6574FFFF0102"), Err(FormatError::AddressOutOfRange { line: 1 }));
+        assert_eq!(parse("%1061C40100C30001\n"), Err(FormatError::MissingEndOfFile));
+    }
+}